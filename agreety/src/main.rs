@@ -72,7 +72,13 @@ fn login(node: &str, cmd: &mut Option<String>) -> Result<LoginResult, Box<dyn st
 
     let mut stream = UnixStream::connect(env::var("GREETD_SOCK")?)?;
 
-    let mut next_request = Request::CreateSession { username };
+    let mut next_request = Request::CreateSession {
+        username,
+        session_cookie: None,
+        xkb_layout: None,
+        xkb_variant: None,
+        xkb_options: None,
+    };
     let mut starting = false;
     loop {
         next_request.write_to(&mut stream)?;
@@ -81,6 +87,7 @@ fn login(node: &str, cmd: &mut Option<String>) -> Result<LoginResult, Box<dyn st
             Response::AuthMessage {
                 auth_message,
                 auth_message_type,
+                ..
             } => {
                 let response = match auth_message_type {
                     AuthMessageType::Visible => Some(prompt_stderr(&auth_message)?),
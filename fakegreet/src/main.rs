@@ -12,7 +12,7 @@ use tokio::{
 use crate::error::Error;
 use greetd_ipc::{
     codec::{Error as CodecError, TokioCodec},
-    AuthMessageType, ErrorType, Request, Response,
+    AuthMessageType, ErrorType, Request, Response, Severity,
 };
 
 fn wrap_result<T>(res: Result<T, Error>) -> Response {
@@ -109,9 +109,11 @@ impl Context {
 
 async fn client_get_question(ctx: &Context) -> Response {
     match ctx.get_question().await {
+        // Every question asked here is a prompt.
         Ok(Some((auth_message_type, auth_message))) => Response::AuthMessage {
             auth_message_type,
             auth_message,
+            auth_message_severity: Some(Severity::Notice),
         },
         res => wrap_result(res),
     }
@@ -127,7 +129,7 @@ async fn client_handler(ctx: &Context, mut s: UnixStream) -> Result<(), Error> {
 
         println!("req: {:?}", req);
         let resp = match req {
-            Request::CreateSession { username } => match ctx.create_session(username).await {
+            Request::CreateSession { username, .. } => match ctx.create_session(username).await {
                 Ok(()) => client_get_question(&ctx).await,
                 res => wrap_result(res),
             },
@@ -3,7 +3,7 @@ use std::{collections::HashMap, default::Default, env, fs::read_to_string};
 use enquote::unquote;
 use getopts::Options;
 
-use super::error::Error;
+use super::{error::Error, session::worker::ServicePolicy};
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum VtSelection {
@@ -28,6 +28,8 @@ pub struct ConfigSession {
 #[derive(Debug, Eq, PartialEq, Default)]
 pub struct ConfigInternal {
     pub session_worker: usize,
+    pub service_policy: ServicePolicy,
+    pub self_test: Option<String>,
 }
 
 #[derive(Debug, Eq, PartialEq, Default)]
@@ -38,12 +40,14 @@ pub struct ConfigTerminal {
 #[derive(Debug, Eq, PartialEq)]
 pub struct ConfigGeneral {
     pub source_profile: bool,
+    pub profile_strict: bool,
 }
 
 impl Default for ConfigGeneral {
     fn default() -> Self {
         ConfigGeneral {
             source_profile: true,
+            profile_strict: false,
         }
     }
 }
@@ -54,6 +58,7 @@ pub struct ConfigFile {
     pub general: ConfigGeneral,
     pub default_session: ConfigSession,
     pub initial_session: Option<ConfigSession>,
+    pub class_services: ServicePolicy,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -107,6 +112,7 @@ fn parse_old_config(config: &HashMap<&str, HashMap<&str, &str>>) -> Result<Confi
         },
         general: Default::default(),
         initial_session: None,
+        class_services: Default::default(),
     })
 }
 
@@ -172,15 +178,42 @@ fn parse_new_config(config: &HashMap<&str, HashMap<&str, &str>>) -> Result<Confi
                 .unwrap_or(&"true")
                 .parse()
                 .map_err(|e| format!("could not parse source_profile: {}", e))?,
+            profile_strict: section
+                .get("profile_strict")
+                .unwrap_or(&"false")
+                .parse()
+                .map_err(|e| format!("could not parse profile_strict: {}", e))?,
         },
         None => Default::default(),
     };
 
+    // Each key is a session class, and each value a comma-separated list of
+    // the PAM services allowed for that class.
+    let mut class_services = Vec::new();
+    if let Some(section) = config.get("class_services") {
+        for (class, services) in section {
+            let services = maybe_unquote(services)
+                .map_err(|e| format!("unable to read class_services.{}: {}", class, e))?;
+            let services: Vec<String> = services
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect();
+            if services.is_empty() {
+                return Err(format!("no services specified for class {}", class).into());
+            }
+            class_services.push((class.to_string(), services));
+        }
+        class_services.sort();
+    }
+
     Ok(ConfigFile {
         initial_session,
         default_session,
         general,
         terminal,
+        class_services: ServicePolicy(class_services),
     })
 }
 
@@ -214,6 +247,18 @@ pub fn read_config() -> Result<Config, Error> {
         "start a session worker (internal)",
         "FD",
     );
+    opts.optopt(
+        "",
+        "service-policy",
+        "the PAM services allowed for each session class (internal)",
+        "JSON",
+    );
+    opts.optopt(
+        "",
+        "self-test",
+        "run a trivial session with a PAM service to test greetd",
+        "SERVICE",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => return Err(format!("could not parse arguments: {}", f).into()),
@@ -228,9 +273,15 @@ pub fn read_config() -> Result<Config, Error> {
             .opt_get("session-worker")
             .expect("unable to parse session-worker")
             .unwrap_or(0),
+        service_policy: match matches.opt_str("service-policy") {
+            Some(policy) => serde_json::from_str(&policy)
+                .map_err(|e| format!("could not parse service-policy: {}", e))?,
+            None => Default::default(),
+        },
+        self_test: matches.opt_str("self-test"),
     };
 
-    if internal.session_worker > 0 {
+    if internal.session_worker > 0 || internal.self_test.is_some() {
         return Ok(Config {
             file: Default::default(),
             internal,
@@ -296,6 +347,7 @@ greeter_user = \"greeter\"
                 },
                 general: Default::default(),
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
 
@@ -318,6 +370,7 @@ greeter = \"agreety\"
                 },
                 general: Default::default(),
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
     }
@@ -345,6 +398,7 @@ command = \"agreety\"
                 },
                 general: Default::default(),
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
     }
@@ -375,6 +429,7 @@ user = \"john\"
                     command: "sway".to_string(),
                     user: "john".to_string(),
                 }),
+                class_services: Default::default(),
             }
         );
     }
@@ -386,6 +441,7 @@ user = \"john\"
 [terminal]\nvt = 1\n[default_session]\ncommand = \"agreety\"
 [general]
 source_profile = false
+profile_strict = true
 ",
         )
         .expect("config didn't parse");
@@ -401,8 +457,10 @@ source_profile = false
                 },
                 general: ConfigGeneral {
                     source_profile: false,
+                    profile_strict: true,
                 },
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
     }
@@ -414,11 +472,51 @@ source_profile = false
 [terminal]\nvt = 1\n[default_session]\ncommand = \"agreety\"
 [general]
 source_profile = fals
+",
+        )
+        .is_err());
+        assert!(parse_config(
+            "
+[terminal]\nvt = 1\n[default_session]\ncommand = \"agreety\"
+[general]
+profile_strict = yes
 ",
         )
         .is_err())
     }
 
+    #[test]
+    fn class_services() {
+        let config = parse_config(
+            "
+[terminal]\nvt = 1\n[default_session]\ncommand = \"agreety\"
+[class_services]
+user = \"greetd, login\"
+greeter = \"greetd-greeter\"
+",
+        )
+        .expect("config didn't parse");
+        assert_eq!(
+            config.class_services,
+            ServicePolicy(vec![
+                ("greeter".to_string(), vec!["greetd-greeter".to_string()]),
+                (
+                    "user".to_string(),
+                    vec!["greetd".to_string(), "login".to_string()]
+                ),
+            ])
+        );
+
+        assert!(parse_config(
+            "
+[terminal]\nvt = 1\n[default_session]\ncommand = \"agreety\"
+[class_services]
+user = \"\"
+",
+        )
+        .is_err());
+    }
+
     #[test]
     fn terminal() {
         let config = parse_config(
@@ -441,6 +539,7 @@ vt = 1
                 },
                 general: Default::default(),
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
         let config = parse_config(
@@ -463,6 +562,7 @@ vt = next
                 },
                 general: Default::default(),
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
         let config = parse_config(
@@ -485,6 +585,7 @@ vt = current
                 },
                 general: Default::default(),
                 initial_session: None,
+                class_services: Default::default(),
             }
         );
     }
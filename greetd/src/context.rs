@@ -9,11 +9,14 @@ use tokio::{sync::RwLock, time::delay_for};
 use crate::{
     error::Error,
     session::{
-        interface::{Session, SessionChild, SessionState},
-        worker::{AuthMessageType as SessAuthMessageType, TerminalMode},
+        interface::{Session, SessionChild, SessionState, StartMode},
+        worker::{
+            AuthMessageType as SessAuthMessageType, LoginOptions, ServicePolicy, SessionCookie,
+            Severity as SessSeverity, TerminalMode,
+        },
     },
 };
-use greetd_ipc::AuthMessageType;
+use greetd_ipc::{AuthMessageType, Severity};
 
 struct SessionChildSet {
     child: SessionChild,
@@ -41,9 +44,12 @@ pub struct Context {
     pam_service: String,
     term_mode: TerminalMode,
     source_profile: bool,
+    options: LoginOptions,
+    policy: ServicePolicy,
 }
 
 impl Context {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         greeter_bin: String,
         greeter_user: String,
@@ -51,6 +57,8 @@ impl Context {
         pam_service: String,
         term_mode: TerminalMode,
         source_profile: bool,
+        options: LoginOptions,
+        policy: ServicePolicy,
     ) -> Context {
         Context {
             inner: RwLock::new(ContextInner {
@@ -64,6 +72,8 @@ impl Context {
             pam_service,
             term_mode,
             source_profile,
+            options,
+            policy,
         }
     }
 
@@ -77,7 +87,7 @@ impl Context {
         service: &str,
         cmd: Vec<String>,
     ) -> Result<SessionChild, Error> {
-        let mut scheduled_session = Session::new_external()?;
+        let mut scheduled_session = Session::new_external(&self.policy)?;
         scheduled_session
             .initiate(
                 &service,
@@ -86,18 +96,19 @@ impl Context {
                 false,
                 &self.term_mode,
                 self.source_profile,
+                &self.options,
             )
             .await?;
         loop {
             match scheduled_session.get_state().await {
                 Ok(SessionState::Ready) => break,
-                Ok(SessionState::Question(_, _)) => scheduled_session.post_response(None).await?,
+                Ok(SessionState::Question(..)) => scheduled_session.post_response(None).await?,
                 Err(err) => return Err(format!("session start failed: {}", err).into()),
             }
         }
 
         scheduled_session.send_args(cmd).await?;
-        scheduled_session.start().await
+        scheduled_session.start(StartMode::Fresh).await
     }
 
     /// Directly start a greeter session, bypassing the normal scheduling. This
@@ -151,8 +162,16 @@ impl Context {
         Ok(())
     }
 
-    /// Create a new session for configuration.
-    pub async fn create_session(&self, username: String) -> Result<(), Error> {
+    /// Create a new session for configuration, with the session cookie and
+    /// keyboard layout provided by the greeter, if any.
+    pub async fn create_session(
+        &self,
+        username: String,
+        session_cookie: Option<String>,
+        xkb_layout: Option<String>,
+        xkb_variant: Option<String>,
+        xkb_options: Option<String>,
+    ) -> Result<(), Error> {
         {
             let inner = self.inner.read().await;
             if inner.current.is_none() {
@@ -166,8 +185,15 @@ impl Context {
             }
         }
 
+        let options = LoginOptions {
+            session_cookie: session_cookie.map(SessionCookie),
+            xkb_layout,
+            xkb_variant,
+            xkb_options,
+            ..self.options.clone()
+        };
         let mut session_set = SessionSet {
-            session: Session::new_external()?,
+            session: Session::new_external(&self.policy)?,
             time: Instant::now(),
         };
         session_set
@@ -179,6 +205,7 @@ impl Context {
                 true,
                 &self.term_mode,
                 self.source_profile,
+                &options,
             )
             .await?;
 
@@ -189,34 +216,39 @@ impl Context {
 
         // If there was a session under configuration, cancel it.
         if let Some(mut s) = session {
-            s.session.cancel().await?;
+            s.session.cancel(Some("replaced by a new session")).await?;
         }
 
         Ok(())
     }
 
-    /// Cancel the session being configured.
-    pub async fn cancel(&self) -> Result<(), Error> {
+    /// Cancel the session being configured, explaining why for the logs.
+    pub async fn cancel(&self, reason: &str) -> Result<(), Error> {
         let mut inner = self.inner.write().await;
         if let Some(mut s) = inner.configuring.take() {
-            s.session.cancel().await?;
+            s.session.cancel(Some(reason)).await?;
         }
         Ok(())
     }
 
     /// Retrieve a question from the session under configuration.
-    pub async fn get_question(&self) -> Result<Option<(AuthMessageType, String)>, Error> {
+    pub async fn get_question(&self) -> Result<Option<(AuthMessageType, Severity, String)>, Error> {
         let mut inner = self.inner.write().await;
         match &mut inner.configuring {
             Some(s) => match s.session.get_state().await? {
                 SessionState::Ready => Ok(None),
-                SessionState::Question(style, string) => Ok(Some((
+                SessionState::Question(style, severity, string) => Ok(Some((
                     match style {
                         SessAuthMessageType::Visible => AuthMessageType::Visible,
                         SessAuthMessageType::Secret => AuthMessageType::Secret,
                         SessAuthMessageType::Info => AuthMessageType::Info,
                         SessAuthMessageType::Error => AuthMessageType::Error,
                     },
+                    match severity {
+                        SessSeverity::Error => Severity::Error,
+                        SessSeverity::Notice => Severity::Notice,
+                        SessSeverity::Info => Severity::Info,
+                    },
                     string,
                 ))),
             },
@@ -249,7 +281,9 @@ impl Context {
 
                     // If there was a scheduled session, cancel it.
                     if let Some(mut p) = session {
-                        p.session.cancel().await?;
+                        p.session
+                            .cancel(Some("replaced by a new scheduled session"))
+                            .await?;
                     }
 
                     // We give the greeter 5 seconds to prove itself well-behaved before
@@ -285,7 +319,8 @@ impl Context {
                 return Ok(());
             }
             drop(inner);
-            let s = match p.session.start().await {
+            // Scheduled sessions are created by the greeter, which is gone.
+            let s = match p.session.start(StartMode::HandOff).await {
                 Ok(s) => s,
                 Err(e) => return Err(format!("session start failed: {}", e).into()),
             };
@@ -311,9 +346,9 @@ impl Context {
                 // We got an exit, see if it's something we need to clean up.
                 Ok(WaitStatus::Exited(pid, ..)) | Ok(WaitStatus::Signaled(pid, ..)) => {
                     let mut inner = self.inner.write().await;
-                    let (was_greeter, sesion_length) = match &inner.current {
+                    let (was_greeter, sesion_length, started_at) = match &inner.current {
                         Some(s) if s.child.owns_pid(pid) => {
-                            let res = (s.is_greeter, s.time.elapsed());
+                            let res = (s.is_greeter, s.time.elapsed(), s.child.started_at);
                             inner.current = None;
                             res
                         }
@@ -325,7 +360,12 @@ impl Context {
                             // Our greeter finally bit the dust so we can
                             // start our scheduled session.
                             drop(inner);
-                            let s = match scheduled.session.start().await {
+                            let mode = if was_greeter {
+                                StartMode::HandOff
+                            } else {
+                                StartMode::Fresh
+                            };
+                            let s = match scheduled.session.start(mode).await {
                                 Ok(s) => s,
                                 Err(e) => return Err(format!("session start failed: {}", e).into()),
                             };
@@ -340,6 +380,8 @@ impl Context {
                             if was_greeter {
                                 return Err("greeter exited without creating a session".into());
                             }
+                            let ran = started_at.elapsed().unwrap_or_default();
+                            eprintln!("session exited after {}s", ran.as_secs());
                             if sesion_length < Duration::from_secs(1) {
                                 delay_for(Duration::from_secs(1)).await;
                             }
@@ -372,10 +414,10 @@ impl Context {
     pub async fn terminate(&self) -> Result<(), Error> {
         let mut inner = self.inner.write().await;
         if let Some(mut sess) = inner.configuring.take() {
-            let _ = sess.session.cancel().await;
+            let _ = sess.session.cancel(Some("greetd is terminating")).await;
         }
         if let Some(mut sess) = inner.scheduled.take() {
-            let _ = sess.session.cancel().await;
+            let _ = sess.session.cancel(Some("greetd is terminating")).await;
         }
         if let Some(sess) = inner.current.take() {
             sess.child.term();
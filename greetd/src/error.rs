@@ -19,6 +19,9 @@ pub enum Error {
 
     #[error("configuration error: {0}")]
     ConfigError(String),
+
+    #[error("peer disconnected")]
+    PeerDisconnected,
 }
 
 impl From<Box<dyn std::error::Error>> for Error {
@@ -0,0 +1,7 @@
+//! The session logic of greetd, for driving logins without the daemon. See
+//! [`session::login`] for the entry point.
+
+pub mod error;
+pub mod pam;
+pub mod session;
+pub mod terminal;
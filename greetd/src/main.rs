@@ -1,32 +1,43 @@
 mod config;
 mod context;
-mod error;
-mod pam;
 mod scrambler;
+mod selftest;
 mod server;
-mod session;
-mod terminal;
 
 use std::os::unix::{
     io::{FromRawFd, RawFd},
     net::UnixDatagram,
 };
 
-use nix::{
-    fcntl::{fcntl, FcntlArg, FdFlag},
-    sys::mman::{mlockall, MlockAllFlags},
-};
+use nix::sys::mman::{mlockall, MlockAllFlags};
 use tokio::task;
 
+use greetd::{error, pam, session, terminal};
+
 use crate::{error::Error, session::worker};
 
 async fn session_worker_main(config: config::Config) -> Result<(), Error> {
     let raw_fd = config.internal.session_worker as RawFd;
-    let mut cur_flags = unsafe { FdFlag::from_bits_unchecked(fcntl(raw_fd, FcntlArg::F_GETFD)?) };
-    cur_flags.insert(FdFlag::FD_CLOEXEC);
-    fcntl(raw_fd, FcntlArg::F_SETFD(cur_flags))?;
+    // Keep the control socket from being inherited by the session.
+    worker::set_cloexec(raw_fd, true)?;
     let sock = unsafe { UnixDatagram::from_raw_fd(raw_fd) };
-    worker::main(&sock)
+    worker::main(&sock, &config.internal.service_policy)
+}
+
+fn self_test_main(service: &str) -> Result<(), Error> {
+    let user = users::get_current_username().ok_or("unable to get current user")?;
+    let user = user
+        .to_str()
+        .ok_or("current user name is not valid UTF-8")?;
+    let conv = selftest::StubConv {
+        answer: String::new(),
+    };
+    let bin = std::env::current_exe()?;
+    let steps = selftest::run(&bin, service, user, &["/bin/true".to_string()], &conv)?;
+    for step in steps {
+        println!("{}: ok", step);
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -42,10 +53,14 @@ async fn main() {
         eprintln!("config: {:?}", config);
     }
     mlockall(MlockAllFlags::all()).expect("unable to lock pages");
+    // A failed self-test has to be noticed by whatever ran it.
+    let self_test = config.internal.self_test.is_some();
     let res = task::LocalSet::new()
         .run_until(async move {
             if config.internal.session_worker > 0 {
                 session_worker_main(config).await
+            } else if let Some(service) = &config.internal.self_test {
+                self_test_main(service)
             } else {
                 server::main(config).await
             }
@@ -53,5 +68,8 @@ async fn main() {
         .await;
     if let Err(e) = res {
         eprintln!("error: {}", e);
+        if self_test {
+            std::process::exit(1);
+        }
     }
 }
@@ -1,3 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// How to handle PAM messages that are not valid UTF-8, as sent by some
+/// legacy modules in locales with other encodings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ConvEncoding {
+    /// Fail the conversation.
+    #[default]
+    Strict,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+    /// Reinterpret the message as ISO-8859-1.
+    Latin1,
+}
+
 /// A trait representing the PAM authentification conversation
 ///
 /// PAM authentification is done as a conversation mechanism, in which PAM
@@ -8,6 +23,7 @@
 /// This is the trait to implement if you want to customize the conversation with
 /// PAM. If you just want a simple login/password authentication, you can use the
 /// `PasswordConv` implementation provided by this crate.
+#[allow(clippy::result_unit_err)]
 pub trait Converse {
     /// PAM requests a value that should be echoed to the user as they type it
     ///
@@ -1,4 +1,7 @@
-use std::ffi::CStr;
+use std::{
+    ffi::{CStr, CString, NulError, OsStr},
+    os::unix::ffi::OsStrExt,
+};
 
 use libc::c_char;
 use pam_sys::{getenvlist, raw, PamHandle};
@@ -43,3 +46,114 @@ impl Drop for PamEnvList {
         unsafe { raw::pam_misc_drop_env(self.ptr as *mut *mut c_char) };
     }
 }
+
+/// Split an environment entry into its key and value.
+fn split(entry: &CStr) -> (&OsStr, &OsStr) {
+    let bytes = entry.to_bytes();
+    match bytes.iter().position(|&c| c == b'=') {
+        Some(idx) => (
+            OsStr::from_bytes(&bytes[..idx]),
+            OsStr::from_bytes(&bytes[idx + 1..]),
+        ),
+        None => (OsStr::from_bytes(bytes), OsStr::new("")),
+    }
+}
+
+/// An owned copy of a PAM environment, which can be inspected and modified
+/// before it is handed to execve.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PamEnv {
+    vars: Vec<CString>,
+}
+
+impl PamEnv {
+    /// Iterate over the variables as key and value pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.vars.iter().map(|v| split(v))
+    }
+
+    /// Retrieve the value of a variable.
+    pub fn get(&self, key: &str) -> Option<&OsStr> {
+        self.iter()
+            .find(|(k, _)| k.as_bytes() == key.as_bytes())
+            .map(|(_, v)| v)
+    }
+
+    /// Set a variable, replacing any existing value.
+    pub fn set<V: AsRef<OsStr>>(&mut self, key: &str, value: V) -> Result<(), NulError> {
+        let mut entry = Vec::with_capacity(key.len() + 1 + value.as_ref().len());
+        entry.extend_from_slice(key.as_bytes());
+        entry.push(b'=');
+        entry.extend_from_slice(value.as_ref().as_bytes());
+        let entry = CString::new(entry)?;
+        self.remove(key);
+        self.vars.push(entry);
+        Ok(())
+    }
+
+    /// Remove a variable.
+    pub fn remove(&mut self, key: &str) {
+        self.retain(|k, _| k.as_bytes() != key.as_bytes());
+    }
+
+    /// Retain only the variables for which the predicate returns true.
+    pub fn retain<F: FnMut(&OsStr, &OsStr) -> bool>(&mut self, mut f: F) {
+        self.vars.retain(|v| {
+            let (k, v) = split(v);
+            f(k, v)
+        });
+    }
+
+    /// The variables in the KEY=VALUE form expected by execve.
+    pub fn to_vec(&self) -> Vec<&CStr> {
+        self.vars.iter().map(|v| v.as_c_str()).collect()
+    }
+}
+
+impl From<&PamEnvList> for PamEnv {
+    fn from(list: &PamEnvList) -> PamEnv {
+        PamEnv {
+            vars: list.to_vec().into_iter().map(|v| v.to_owned()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pam_env() {
+        let mut env = PamEnv::default();
+        env.set("HOME", "/home/john").unwrap();
+        env.set("SHELL", "/bin/sh").unwrap();
+        env.set("EMPTY", "").unwrap();
+        assert_eq!(env.get("HOME"), Some(OsStr::new("/home/john")));
+        assert_eq!(env.get("EMPTY"), Some(OsStr::new("")));
+        assert_eq!(env.get("HOM"), None);
+
+        env.set("HOME", "/home/jane").unwrap();
+        assert_eq!(
+            env.iter().collect::<Vec<_>>(),
+            vec![
+                (OsStr::new("SHELL"), OsStr::new("/bin/sh")),
+                (OsStr::new("EMPTY"), OsStr::new("")),
+                (OsStr::new("HOME"), OsStr::new("/home/jane")),
+            ]
+        );
+
+        env.remove("EMPTY");
+        env.retain(|k, _| k != "SHELL");
+        assert_eq!(
+            env.to_vec(),
+            vec![CStr::from_bytes_with_nul(b"HOME=/home/jane\0").unwrap()]
+        );
+
+        assert!(env.set("BAD", "nul\0byte").is_err());
+        assert_eq!(env.get("BAD"), None);
+
+        let value = OsStr::from_bytes(b"/home/\xff");
+        env.set("HOME", value).unwrap();
+        assert_eq!(env.get("HOME"), Some(value));
+    }
+}
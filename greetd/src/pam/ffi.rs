@@ -1,4 +1,6 @@
 use std::{
+    borrow::Cow,
+    cell::RefCell,
     ffi::{CStr, CString},
     mem,
     pin::Pin,
@@ -7,10 +9,30 @@ use std::{
 use libc::{c_int, c_void, calloc, free, size_t, strdup};
 use pam_sys::{PamConversation, PamMessage, PamMessageStyle, PamResponse, PamReturnCode};
 
-use super::converse::Converse;
+use super::converse::{ConvEncoding, Converse};
 
 pub struct PamConvHandlerWrapper<'a> {
     pub handler: Pin<Box<dyn Converse + 'a>>,
+    /// The last error message sent by PAM, which often explains which
+    /// module failed and why.
+    pub last_error: RefCell<Option<String>>,
+    /// How to decode messages that are not valid UTF-8.
+    pub encoding: ConvEncoding,
+}
+
+/// Decode a PAM message, or return None if it cannot be decoded with the
+/// specified encoding.
+fn decode(msg: &CStr, encoding: ConvEncoding) -> Option<Cow<'_, str>> {
+    match msg.to_str() {
+        Ok(m) => Some(Cow::Borrowed(m)),
+        Err(_) => match encoding {
+            ConvEncoding::Strict => None,
+            ConvEncoding::Lossy => Some(msg.to_string_lossy()),
+            ConvEncoding::Latin1 => Some(Cow::Owned(
+                msg.to_bytes().iter().map(|&c| c as char).collect(),
+            )),
+        },
+    }
 }
 
 pub fn make_conversation(conv: &mut PamConvHandlerWrapper) -> PamConversation {
@@ -42,13 +64,14 @@ pub extern "C" fn converse(
         let m: &mut PamMessage = unsafe { &mut **(msg.offset(i)) };
         let r: &mut PamResponse = unsafe { &mut *(resp.offset(i)) };
         let msg = unsafe { CStr::from_ptr(m.msg) };
-        let msg = match msg.to_str() {
-            Ok(m) => m,
-            Err(_) => {
+        let msg = match decode(msg, wrapper.encoding) {
+            Some(m) => m,
+            None => {
                 result = PamReturnCode::CONV_ERR;
                 break;
             }
         };
+        let msg = &*msg;
         // match on msg_style
         match PamMessageStyle::from(m.msg_style) {
             PamMessageStyle::PROMPT_ECHO_ON => {
@@ -70,6 +93,7 @@ pub extern "C" fn converse(
                 }
             }
             PamMessageStyle::ERROR_MSG => {
+                wrapper.last_error.replace(Some(msg.to_string()));
                 if wrapper.handler.error(msg).is_err() {
                     result = PamReturnCode::CONV_ERR;
                 }
@@ -103,3 +127,141 @@ pub extern "C" fn converse(
 
     result as c_int
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    struct NullConv;
+
+    impl Converse for NullConv {
+        fn prompt_echo(&self, _msg: &str) -> Result<String, ()> {
+            Err(())
+        }
+        fn prompt_blind(&self, _msg: &str) -> Result<String, ()> {
+            Err(())
+        }
+        fn info(&self, _msg: &str) -> Result<(), ()> {
+            Ok(())
+        }
+        fn error(&self, _msg: &str) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    struct EchoConv;
+
+    impl Converse for EchoConv {
+        fn prompt_echo(&self, msg: &str) -> Result<String, ()> {
+            Ok(msg.to_string())
+        }
+        fn prompt_blind(&self, msg: &str) -> Result<String, ()> {
+            Ok(msg.to_string())
+        }
+        fn info(&self, _msg: &str) -> Result<(), ()> {
+            Ok(())
+        }
+        fn error(&self, _msg: &str) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn new_wrapper<'a>(
+        handler: Pin<Box<dyn Converse + 'a>>,
+        encoding: ConvEncoding,
+    ) -> PamConvHandlerWrapper<'a> {
+        PamConvHandlerWrapper {
+            handler,
+            last_error: RefCell::new(None),
+            encoding,
+        }
+    }
+
+    /// Send a prompt, returning the response if the conversation succeeded.
+    fn send_prompt(wrapper: &mut PamConvHandlerWrapper, msg: &[u8]) -> Option<String> {
+        let msg = CString::new(msg).unwrap();
+        let mut m = PamMessage {
+            msg_style: PamMessageStyle::PROMPT_ECHO_ON as c_int,
+            msg: msg.as_ptr(),
+        };
+        let mut msgs = &mut m as *mut PamMessage;
+        let mut resp: *mut PamResponse = ptr::null_mut();
+        let rc = converse(
+            1,
+            &mut msgs,
+            &mut resp,
+            wrapper as *mut PamConvHandlerWrapper as *mut c_void,
+        );
+        if rc != PamReturnCode::SUCCESS as c_int {
+            return None;
+        }
+        let answer = unsafe {
+            let answer = CStr::from_ptr((*resp).resp).to_str().unwrap().to_string();
+            free((*resp).resp as *mut c_void);
+            free(resp as *mut c_void);
+            answer
+        };
+        Some(answer)
+    }
+
+    fn send_message(wrapper: &mut PamConvHandlerWrapper, style: PamMessageStyle, msg: &str) {
+        let msg = CString::new(msg).unwrap();
+        let mut m = PamMessage {
+            msg_style: style as c_int,
+            msg: msg.as_ptr(),
+        };
+        let mut msgs = &mut m as *mut PamMessage;
+        let mut resp: *mut PamResponse = ptr::null_mut();
+        let rc = converse(
+            1,
+            &mut msgs,
+            &mut resp,
+            wrapper as *mut PamConvHandlerWrapper as *mut c_void,
+        );
+        assert_eq!(rc, PamReturnCode::SUCCESS as c_int);
+        unsafe { free(resp as *mut c_void) };
+    }
+
+    #[test]
+    fn last_error() {
+        let mut wrapper = new_wrapper(Box::pin(NullConv), ConvEncoding::Strict);
+
+        send_message(&mut wrapper, PamMessageStyle::TEXT_INFO, "hello");
+        assert_eq!(*wrapper.last_error.borrow(), None);
+
+        send_message(
+            &mut wrapper,
+            PamMessageStyle::ERROR_MSG,
+            "pam_faillock: account locked",
+        );
+        assert_eq!(
+            wrapper.last_error.borrow().as_deref(),
+            Some("pam_faillock: account locked")
+        );
+    }
+
+    #[test]
+    fn encoding() {
+        let prompt = b"Contrase\xf1a:";
+
+        let mut wrapper = new_wrapper(Box::pin(EchoConv), ConvEncoding::Strict);
+        assert_eq!(
+            send_prompt(&mut wrapper, b"Password:").as_deref(),
+            Some("Password:")
+        );
+        assert_eq!(send_prompt(&mut wrapper, prompt), None);
+
+        let mut wrapper = new_wrapper(Box::pin(EchoConv), ConvEncoding::Lossy);
+        assert_eq!(
+            send_prompt(&mut wrapper, prompt).as_deref(),
+            Some("Contrase\u{fffd}a:")
+        );
+
+        let mut wrapper = new_wrapper(Box::pin(EchoConv), ConvEncoding::Latin1);
+        assert_eq!(
+            send_prompt(&mut wrapper, prompt).as_deref(),
+            Some("Contraseña:")
+        );
+    }
+}
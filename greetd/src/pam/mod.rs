@@ -1,5 +1,5 @@
 pub mod converse;
-mod env;
+pub mod env;
 mod ffi;
 pub mod session;
 
@@ -28,8 +28,108 @@ impl PamError {
             | PamReturnCode::CRED_INSUFFICIENT
             | PamReturnCode::USER_UNKNOWN
             | PamReturnCode::PERM_DENIED
+            | PamReturnCode::AUTHTOK_ERR
+            | PamReturnCode::AUTHTOK_RECOVERY_ERR
             | PamReturnCode::SERVICE_ERR => PamError::AuthError(format!("{}: {:?}", prefix, rc)),
             _ => PamError::Error(format!("{}: {:?}", prefix, rc)),
         }
     }
+
+    /// Attach the last error message sent by PAM during the failed call.
+    pub fn with_message(self, msg: Option<String>) -> PamError {
+        let msg = match msg {
+            Some(msg) => msg,
+            None => return self,
+        };
+        match self {
+            PamError::Error(s) => PamError::Error(format!("{} ({})", s, msg)),
+            PamError::AuthError(s) => PamError::AuthError(format!("{} ({})", s, msg)),
+            PamError::AbortError(s) => PamError::AbortError(format!("{} ({})", s, msg)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    use converse::{ConvEncoding, Converse};
+    use pam_sys::PamFlag;
+    use session::PamSession;
+
+    #[test]
+    fn with_message() {
+        let err = PamError::from_rc("pam_authenticate", PamReturnCode::AUTH_ERR);
+        assert_eq!(
+            err.with_message(None).to_string(),
+            "pam_authenticate: AUTH_ERR"
+        );
+
+        let err = PamError::from_rc("pam_authenticate", PamReturnCode::AUTH_ERR)
+            .with_message(Some("pam_faillock: account locked".to_string()));
+        assert!(matches!(err, PamError::AuthError(_)));
+        assert_eq!(
+            err.to_string(),
+            "pam_authenticate: AUTH_ERR (pam_faillock: account locked)"
+        );
+    }
+
+    /// Records the messages PAM sends, and declines every prompt.
+    #[derive(Default)]
+    struct RecordingConv {
+        msgs: RefCell<Vec<String>>,
+    }
+
+    impl Converse for &RecordingConv {
+        fn prompt_echo(&self, _msg: &str) -> Result<String, ()> {
+            Err(())
+        }
+        fn prompt_blind(&self, _msg: &str) -> Result<String, ()> {
+            Err(())
+        }
+        fn info(&self, msg: &str) -> Result<(), ()> {
+            self.msgs.borrow_mut().push(format!("info: {}", msg));
+            Ok(())
+        }
+        fn error(&self, msg: &str) -> Result<(), ()> {
+            self.msgs.borrow_mut().push(format!("error: {}", msg));
+            Ok(())
+        }
+    }
+
+    /// Run with `sudo cargo test -- --ignored pam_error_message`, after
+    /// installing tests/pam.d/greetd-error-message in /etc/pam.d.
+    #[test]
+    #[ignore]
+    fn pam_error_message() {
+        let conv = RecordingConv::default();
+        let mut pam = PamSession::start(
+            "greetd-error-message",
+            "nobody",
+            Box::pin(&conv),
+            ConvEncoding::default(),
+        )
+        .unwrap();
+        let err = pam.authenticate(PamFlag::NONE).unwrap_err();
+        pam.end().unwrap();
+
+        // Only the error message is attached, not the info message before it.
+        let failure = "/bin/false failed: exit code 1";
+        assert_eq!(
+            *conv.msgs.borrow(),
+            vec![
+                "info: greetd error message test".to_string(),
+                format!("error: {}", failure)
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} ({})",
+                PamError::from_rc("pam_authenticate", PamReturnCode::SYSTEM_ERR),
+                failure
+            )
+        );
+    }
 }
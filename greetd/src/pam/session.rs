@@ -1,25 +1,48 @@
 use std::{
-    ffi::{CStr, CString},
-    os::raw::c_char,
+    cell::RefCell,
+    ffi::{CStr, CString, OsStr},
+    os::{raw::c_char, unix::ffi::OsStrExt},
     pin::Pin,
     ptr,
+    sync::Mutex,
 };
 
 use libc::c_void;
+use nix::unistd::{getpid, Pid};
 use pam_sys::{PamFlag, PamHandle, PamItemType, PamReturnCode};
 
 use super::{
-    converse::Converse,
-    env::{get_pam_env, PamEnvList},
+    converse::{ConvEncoding, Converse},
+    env::{get_pam_env, PamEnv},
     ffi::{make_conversation, PamConvHandlerWrapper},
     PamError,
 };
 
+/// The handles of the transactions started by this process that have not
+/// been ended, so that a process dying at a panic can still end them.
+static OPEN_HANDLES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// End every transaction this process has left open. This is for a process
+/// that is about to exit without unwinding, as it leaves the sessions that
+/// own the transactions referring to ended ones.
+pub fn end_abandoned() {
+    // The panic may have struck while the lock was held.
+    if let Ok(mut handles) = OPEN_HANDLES.try_lock() {
+        for handle in handles.drain(..) {
+            pam_sys::end(
+                unsafe { &mut *(handle as *mut PamHandle) },
+                PamReturnCode::ABORT,
+            );
+        }
+    }
+}
+
 pub struct PamSession<'a> {
     handle: &'a mut PamHandle,
-    #[allow(unused)]
     lifetime_extender: Pin<Box<PamConvHandlerWrapper<'a>>>,
     last_code: PamReturnCode,
+    owner: Pid,
+    ended: bool,
 }
 
 impl<'a> PamSession<'a> {
@@ -27,63 +50,86 @@ impl<'a> PamSession<'a> {
         service: &str,
         user: &'a str,
         pam_conv: Pin<Box<dyn Converse + 'a>>,
+        encoding: ConvEncoding,
     ) -> Result<PamSession<'a>, PamError> {
-        let mut pch = Box::pin(PamConvHandlerWrapper { handler: pam_conv });
+        let mut pch = Box::pin(PamConvHandlerWrapper {
+            handler: pam_conv,
+            last_error: RefCell::new(None),
+            encoding,
+        });
         let conv = make_conversation(&mut *pch);
         let mut pam_handle: *mut PamHandle = ptr::null_mut();
 
         match pam_sys::start(service, Some(user), &conv, &mut pam_handle) {
-            PamReturnCode::SUCCESS => Ok(PamSession {
-                handle: unsafe { &mut *pam_handle },
-                lifetime_extender: pch,
-                last_code: PamReturnCode::SUCCESS,
-            }),
+            PamReturnCode::SUCCESS => {
+                if let Ok(mut handles) = OPEN_HANDLES.lock() {
+                    handles.push(pam_handle as usize);
+                }
+                Ok(PamSession {
+                    handle: unsafe { &mut *pam_handle },
+                    lifetime_extender: pch,
+                    last_code: PamReturnCode::SUCCESS,
+                    owner: getpid(),
+                    ended: false,
+                })
+            }
             rc => Err(PamError::from_rc("pam_start", rc)),
         }
     }
 
-    pub fn authenticate(&mut self, flags: PamFlag) -> Result<(), PamError> {
-        self.last_code = pam_sys::authenticate(self.handle, flags);
+    /// Convert the last return code to a result, attaching any error message
+    /// sent through the conversation during the call.
+    fn conv_result(&self, prefix: &str) -> Result<(), PamError> {
         match self.last_code {
             PamReturnCode::SUCCESS => Ok(()),
-            rc => Err(PamError::from_rc("pam_authenticate", rc)),
+            rc => Err(PamError::from_rc(prefix, rc)
+                .with_message(self.lifetime_extender.last_error.borrow_mut().take())),
         }
     }
 
+    pub fn authenticate(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        self.lifetime_extender.last_error.replace(None);
+        self.last_code = pam_sys::authenticate(self.handle, flags);
+        self.conv_result("pam_authenticate")
+    }
+
+    pub fn chauthtok(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        self.lifetime_extender.last_error.replace(None);
+        self.last_code = pam_sys::chauthtok(self.handle, flags);
+        self.conv_result("pam_chauthtok")
+    }
+
     pub fn acct_mgmt(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        self.lifetime_extender.last_error.replace(None);
         self.last_code = pam_sys::acct_mgmt(self.handle, flags);
-        match self.last_code {
-            PamReturnCode::SUCCESS => Ok(()),
-            rc => Err(PamError::from_rc("pam_acct_mgmt", rc)),
-        }
+        self.conv_result("pam_acct_mgmt")
     }
 
     pub fn setcred(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        self.lifetime_extender.last_error.replace(None);
         self.last_code = pam_sys::setcred(self.handle, flags);
-        match self.last_code {
-            PamReturnCode::SUCCESS => Ok(()),
-            rc => Err(PamError::from_rc("pam_setcred", rc)),
-        }
+        self.conv_result("pam_setcred")
     }
 
     pub fn open_session(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        self.lifetime_extender.last_error.replace(None);
         self.last_code = pam_sys::open_session(self.handle, flags);
-        match self.last_code {
-            PamReturnCode::SUCCESS => Ok(()),
-            rc => Err(PamError::from_rc("pam_open_session", rc)),
-        }
+        self.conv_result("pam_open_session")
     }
 
     pub fn close_session(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        self.lifetime_extender.last_error.replace(None);
         self.last_code = pam_sys::close_session(self.handle, flags);
-        match self.last_code {
-            PamReturnCode::SUCCESS => Ok(()),
-            rc => Err(PamError::from_rc("pam_close_session", rc)),
-        }
+        self.conv_result("pam_close_session")
     }
 
-    pub fn putenv(&mut self, v: &str) -> Result<(), PamError> {
-        self.last_code = pam_sys::putenv(self.handle, v);
+    pub fn putenv<V: AsRef<OsStr>>(&mut self, v: V) -> Result<(), PamError> {
+        self.last_code = match CString::new(v.as_ref().as_bytes()) {
+            Ok(v) => {
+                PamReturnCode::from(unsafe { pam_sys::raw::pam_putenv(self.handle, v.as_ptr()) })
+            }
+            Err(_) => PamReturnCode::BUF_ERR,
+        };
         match self.last_code {
             PamReturnCode::SUCCESS => Ok(()),
             rc => Err(PamError::from_rc("pam_putenv", rc)),
@@ -114,19 +160,45 @@ impl<'a> PamSession<'a> {
         }
     }
 
-    pub fn getenvlist(&mut self) -> Result<PamEnvList, PamError> {
+    pub fn getenvlist(&mut self) -> Result<PamEnv, PamError> {
         match get_pam_env(self.handle) {
-            Some(v) => Ok(v),
+            Some(v) => Ok(PamEnv::from(&v)),
             None => Err(PamError::Error(
                 "unable to retrieve environment".to_string(),
             )),
         }
     }
 
+    /// Returns the return code of the last PAM call made on this session.
+    pub fn last_code(&self) -> PamReturnCode {
+        self.last_code
+    }
+
     pub fn end(&mut self) -> Result<(), PamError> {
+        if self.ended {
+            return Ok(());
+        }
+        self.ended = true;
+        if let Ok(mut handles) = OPEN_HANDLES.lock() {
+            let handle = self.handle as *mut PamHandle as usize;
+            handles.retain(|&h| h != handle);
+        }
         match pam_sys::end(self.handle, self.last_code) {
             PamReturnCode::SUCCESS => Ok(()),
             rc => Err(PamError::from_rc("pam_end", rc)),
         }
     }
 }
+
+impl Drop for PamSession<'_> {
+    /// End a transaction that was abandoned without being ended, such as by
+    /// a panic. Forked children share the transaction with the process that
+    /// started it, so only that process ends it.
+    fn drop(&mut self) {
+        if !self.ended && getpid() == self.owner {
+            if let Err(e) = self.end() {
+                eprintln!("pam: unable to end abandoned transaction: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! A self-test of the login flow, for verifying a deployment end to end.
+//!
+//! The self-test runs a session worker and takes it through a whole login
+//! with a trivial command, as greetd would for a greeter: Ready,
+//! InitiateLogin, the PAM conversation, AuthSuccess, Args, Start,
+//! SessionStarted, ChildExit and Closed. The conversation is answered by a
+//! stub, so the PAM service must admit the user without real credentials,
+//! such as one built from pam_permit.
+
+use std::{
+    fmt,
+    os::unix::{io::AsRawFd, net::UnixDatagram, process::CommandExt},
+    path::Path,
+    process::Command,
+    time::Duration,
+};
+
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::Pid,
+};
+
+use crate::{
+    error::Error,
+    pam::converse::Converse,
+    session::worker::{
+        check_sent, encode, set_cloexec, socket_pair, AuthMessageType, LoginOptions,
+        ParentToSessionChild, SessionChildToParent, TerminalMode, MAX_MESSAGE_SIZE,
+        PROTOCOL_VERSION,
+    },
+};
+
+/// How long to wait for the worker to take the next step before giving up.
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The steps of the login flow, in the order they are taken.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Ready,
+    InitiateLogin,
+    Conversation,
+    AuthSuccess,
+    Args,
+    Start,
+    SessionStarted,
+    ChildExit,
+    Closed,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// A conversation that answers every prompt with the same answer, and
+/// accepts every message.
+pub struct StubConv {
+    pub answer: String,
+}
+
+impl Converse for StubConv {
+    fn prompt_echo(&self, _msg: &str) -> Result<String, ()> {
+        Ok(self.answer.clone())
+    }
+    fn prompt_blind(&self, _msg: &str) -> Result<String, ()> {
+        Ok(self.answer.clone())
+    }
+    fn info(&self, _msg: &str) -> Result<(), ()> {
+        Ok(())
+    }
+    fn error(&self, _msg: &str) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+/// Answer a PAM message through the conversation. Messages that are not
+/// prompts need no answer.
+fn answer(conv: &dyn Converse, style: AuthMessageType, msg: &str) -> Option<String> {
+    match style {
+        AuthMessageType::Visible => conv.prompt_echo(msg).ok(),
+        AuthMessageType::Secret => conv.prompt_blind(msg).ok(),
+        AuthMessageType::Info => conv.info(msg).ok().and(None),
+        AuthMessageType::Error => conv.error(msg).ok().and(None),
+    }
+}
+
+fn send(sock: &UnixDatagram, msg: &ParentToSessionChild) -> Result<(), Error> {
+    let out = encode(msg)?;
+    check_sent(sock.send(&out), out.len())
+}
+
+fn recv(sock: &UnixDatagram) -> Result<SessionChildToParent, Error> {
+    let mut data = [0; MAX_MESSAGE_SIZE];
+    match sock.recv(&mut data[..])? {
+        0 => Err(Error::PeerDisconnected),
+        len => Ok(serde_json::from_slice(&data[..len])?),
+    }
+}
+
+/// Produce the error for a message the worker should not have sent at this
+/// step. Errors reported by the worker are passed on as they are.
+fn unexpected(expected: &str, msg: SessionChildToParent) -> Error {
+    match msg {
+        SessionChildToParent::Error(e) => e,
+        msg => Error::ProtocolError(format!("expected {}, got: {:?}", expected, msg)),
+    }
+}
+
+/// Take the worker through the login, recording each step as it completes.
+fn drive(
+    sock: &UnixDatagram,
+    worker: Pid,
+    service: &str,
+    user: &str,
+    cmd: &[String],
+    conv: &dyn Converse,
+    steps: &mut Vec<Step>,
+) -> Result<(), Error> {
+    match recv(sock)? {
+        SessionChildToParent::Ready { version, .. } if version == PROTOCOL_VERSION => (),
+        SessionChildToParent::Ready { version, .. } => {
+            return Err(Error::ProtocolError(format!(
+                "session worker speaks protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            )))
+        }
+        msg => return Err(unexpected("Ready", msg)),
+    }
+    steps.push(Step::Ready);
+
+    send(
+        sock,
+        &ParentToSessionChild::InitiateLogin {
+            service: service.to_string(),
+            class: "user".to_string(),
+            user: user.to_string(),
+            authenticate: true,
+            tty: TerminalMode::Stdin,
+            source_profile: false,
+            options: Box::new(LoginOptions::default()),
+        },
+    )?;
+    steps.push(Step::InitiateLogin);
+
+    loop {
+        match recv(sock)? {
+            SessionChildToParent::PamMessage { style, msg, .. } => {
+                let resp = answer(conv, style, &msg);
+                send(sock, &ParentToSessionChild::PamResponse { resp })?;
+                if steps.last() != Some(&Step::Conversation) {
+                    steps.push(Step::Conversation);
+                }
+            }
+            SessionChildToParent::Success => break,
+            msg => return Err(unexpected("PamMessage or Success", msg)),
+        }
+    }
+    steps.push(Step::AuthSuccess);
+
+    send(sock, &ParentToSessionChild::Args { cmd: cmd.to_vec() })?;
+    match recv(sock)? {
+        SessionChildToParent::Success => (),
+        msg => return Err(unexpected("Success", msg)),
+    }
+    steps.push(Step::Args);
+
+    send(sock, &ParentToSessionChild::Start)?;
+    steps.push(Step::Start);
+
+    loop {
+        match recv(sock)? {
+            SessionChildToParent::SessionStarted { .. } => steps.push(Step::SessionStarted),
+            SessionChildToParent::FinalChildPid(_)
+                if steps.last() == Some(&Step::SessionStarted) =>
+            {
+                break
+            }
+            msg => return Err(unexpected("SessionStarted and FinalChildPid", msg)),
+        }
+    }
+
+    // The worker ends with the session, and ends the PAM session on its way.
+    match waitpid(worker, None).map_err(|e| format!("unable to wait for worker: {}", e))? {
+        WaitStatus::Exited(_, 0) => (),
+        status => return Err(format!("session worker ended with {:?}", status).into()),
+    }
+    steps.push(Step::ChildExit);
+
+    match recv(sock) {
+        Err(Error::PeerDisconnected) => (),
+        Ok(msg) => return Err(unexpected("the socket to be closed", msg)),
+        Err(e) => return Err(e),
+    }
+    steps.push(Step::Closed);
+
+    Ok(())
+}
+
+/// Start the greetd binary as a session worker on the socket, as greetd does
+/// for its sessions.
+fn spawn_worker(bin: &Path, sock: &UnixDatagram) -> Result<Pid, Error> {
+    let fd = sock.as_raw_fd();
+    let mut cmd = Command::new(bin);
+    cmd.arg("--session-worker").arg(fd.to_string());
+    // The socket is made inheritable only in the child, as for any worker.
+    unsafe {
+        cmd.pre_exec(move || {
+            set_cloexec(fd, false).map_err(|e| std::io::Error::other(e.to_string()))
+        });
+    }
+    let child = cmd
+        .spawn()
+        .map_err(|e| format!("unable to start session worker {}: {}", bin.display(), e))?;
+    Ok(Pid::from_raw(child.id() as i32))
+}
+
+/// Run the self-test with the service for the user, starting the command as
+/// the session and answering the conversation with conv. The session worker
+/// is run from bin, the greetd binary. The steps that were completed are
+/// returned, or the error of the step that failed. This opens a real PAM
+/// session and drops to the user, and thus needs to run as root.
+pub fn run(
+    bin: &Path,
+    service: &str,
+    user: &str,
+    cmd: &[String],
+    conv: &dyn Converse,
+) -> Result<Vec<Step>, Error> {
+    let (parent, child) = socket_pair()?;
+    parent.set_read_timeout(Some(STEP_TIMEOUT))?;
+
+    let worker = spawn_worker(bin, &child)?;
+    drop(child);
+
+    let mut steps = Vec::new();
+    if let Err(e) = drive(&parent, worker, service, user, cmd, conv, &mut steps) {
+        if steps.last() != Some(&Step::ChildExit) {
+            let _ = kill(worker, Signal::SIGKILL);
+            let _ = waitpid(worker, None);
+        }
+        let step = steps
+            .last()
+            .map_or_else(|| "start".to_string(), |s| s.to_string());
+        return Err(format!("self-test failed after {}: {}", step, e).into());
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::getuid;
+    use std::path::Path;
+
+    /// The fixture service, found in tests/pam.d of this crate, which has to
+    /// be installed in /etc/pam.d for the self-test to run.
+    const FIXTURE_SERVICE: &str = "greetd-selftest";
+
+    #[test]
+    fn stub_answers() {
+        let conv = StubConv {
+            answer: "hunter2".to_string(),
+        };
+        assert_eq!(
+            answer(&conv, AuthMessageType::Visible, "Username:"),
+            Some("hunter2".to_string())
+        );
+        assert_eq!(
+            answer(&conv, AuthMessageType::Secret, "Password:"),
+            Some("hunter2".to_string())
+        );
+        assert_eq!(answer(&conv, AuthMessageType::Info, "Welcome"), None);
+        assert_eq!(answer(&conv, AuthMessageType::Error, "Oops"), None);
+    }
+
+    /// The greetd binary built alongside this test binary, which lives in
+    /// the deps directory below it.
+    fn greetd_bin() -> std::path::PathBuf {
+        let exe = std::env::current_exe().unwrap();
+        exe.parent().unwrap().parent().unwrap().join("greetd")
+    }
+
+    /// Run with `cargo build && sudo cargo test -- --ignored login_flow`,
+    /// after installing tests/pam.d/greetd-selftest in /etc/pam.d.
+    #[test]
+    #[ignore]
+    fn login_flow() {
+        assert!(getuid().is_root(), "the self-test needs root");
+        assert!(
+            Path::new("/etc/pam.d").join(FIXTURE_SERVICE).exists(),
+            "the self-test needs /etc/pam.d/{}",
+            FIXTURE_SERVICE
+        );
+        let bin = greetd_bin();
+        let conv = StubConv {
+            answer: "selftest".to_string(),
+        };
+        let cmd = vec!["/bin/true".to_string()];
+        let steps = run(&bin, FIXTURE_SERVICE, "root", &cmd, &conv).unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                Step::Ready,
+                Step::InitiateLogin,
+                Step::Conversation,
+                Step::AuthSuccess,
+                Step::Args,
+                Step::Start,
+                Step::SessionStarted,
+                Step::ChildExit,
+                Step::Closed,
+            ]
+        );
+
+        // The fixture admits anyone, but there is no session for a user
+        // that does not exist.
+        assert!(run(&bin, FIXTURE_SERVICE, "greetd-selftest-nobody", &cmd, &conv).is_err());
+    }
+}
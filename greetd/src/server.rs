@@ -11,7 +11,7 @@ use crate::{
     config::{Config, VtSelection},
     context::Context,
     error::Error,
-    session::worker::TerminalMode,
+    session::worker::{LoginOptions, TerminalMode},
     terminal::{self, Terminal},
 };
 use greetd_ipc::{
@@ -47,9 +47,10 @@ fn wrap_result<T>(res: Result<T, Error>) -> Response {
 
 async fn client_get_question(ctx: &Context) -> Response {
     match ctx.get_question().await {
-        Ok(Some((auth_message_type, auth_message))) => Response::AuthMessage {
+        Ok(Some((auth_message_type, severity, auth_message))) => Response::AuthMessage {
             auth_message_type,
             auth_message,
+            auth_message_severity: Some(severity),
         },
         res => wrap_result(res),
     }
@@ -64,7 +65,22 @@ async fn client_handler(ctx: &Context, mut s: UnixStream) -> Result<(), Error> {
         };
 
         let resp = match req {
-            Request::CreateSession { username } => match ctx.create_session(username).await {
+            Request::CreateSession {
+                username,
+                session_cookie,
+                xkb_layout,
+                xkb_variant,
+                xkb_options,
+            } => match ctx
+                .create_session(
+                    username,
+                    session_cookie,
+                    xkb_layout,
+                    xkb_variant,
+                    xkb_options,
+                )
+                .await
+            {
                 Ok(()) => client_get_question(&ctx).await,
                 res => wrap_result(res),
             },
@@ -75,7 +91,7 @@ async fn client_handler(ctx: &Context, mut s: UnixStream) -> Result<(), Error> {
                 }
             }
             Request::StartSession { cmd } => wrap_result(ctx.start(cmd).await),
-            Request::CancelSession => wrap_result(ctx.cancel().await),
+            Request::CancelSession => wrap_result(ctx.cancel("cancelled by greeter").await),
         };
 
         resp.write_to(&mut s).await?;
@@ -222,6 +238,11 @@ pub async fn main(config: Config) -> Result<(), Error> {
         service.to_string(),
         term_mode.clone(),
         config.file.general.source_profile,
+        LoginOptions {
+            profile_strict: config.file.general.profile_strict,
+            ..Default::default()
+        },
+        config.file.class_services,
     ));
 
     if let Some(s) = config.file.initial_session {
@@ -260,7 +281,10 @@ pub async fn main(config: Config) -> Result<(), Error> {
                     let client_ctx = ctx.clone();
                     task::spawn_local(async move {
                         if let Err(e) = client_handler(&client_ctx, stream).await {
-                            client_ctx.cancel().await.expect("unable to cancel session");
+                            client_ctx
+                                .cancel("greeter connection failed")
+                                .await
+                                .expect("unable to cancel session");
                             eprintln!("client loop failed: {}", e);
                         }
                     });
@@ -0,0 +1,288 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::{getpid, Pid};
+
+use crate::error::Error;
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The directory under the cgroup root in which session cgroups are created.
+const CGROUP_PARENT: &str = "greetd";
+
+/// Write to an existing cgroup interface file.
+fn write_file(path: &Path, value: &str) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)?
+        .write_all(value.as_bytes())
+}
+
+/// Create a directory, accepting one that already exists. This is only for
+/// the greetd hierarchy, which is shared by all sessions.
+fn create_dir(path: &Path) -> io::Result<()> {
+    match fs::create_dir(path) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        res => res,
+    }
+}
+
+/// Check that a limit names an interface file of a controller, such as
+/// "memory.max", in the session cgroup itself.
+fn validate_limit(file: &str) -> Result<(), Error> {
+    let mut parts = file.splitn(2, '.');
+    let controller = parts.next().unwrap_or("");
+    let knob = parts.next().unwrap_or("");
+    if controller.is_empty()
+        || knob.is_empty()
+        || controller == "cgroup"
+        || !file
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+    {
+        return Err(Error::ProtocolError(format!(
+            "invalid cgroup limit: {}",
+            file
+        )));
+    }
+    Ok(())
+}
+
+/// Whether session cgroups are supported by this build and the running system.
+pub fn supported(root: &Path) -> bool {
+    cfg!(feature = "cgroup") && root.join("cgroup.controllers").exists()
+}
+
+/// A cgroup v2 created for a single session, for use on systems where no
+/// service manager places sessions in cgroups.
+pub struct Cgroup {
+    path: PathBuf,
+    owner: Pid,
+    removed: bool,
+}
+
+impl Cgroup {
+    /// Create the named session cgroup under the greetd hierarchy, and apply
+    /// the provided limits, given as pairs of interface file and value such
+    /// as ("memory.max", "2G"). The session cgroup must not exist yet, as
+    /// one that does may still hold the processes of another session, which
+    /// would then be counted against the limits of this one and be torn down
+    /// with it.
+    pub fn create(root: &Path, name: &str, limits: &[(String, String)]) -> Result<Cgroup, Error> {
+        if !cfg!(feature = "cgroup") {
+            return Err("cgroup: support not enabled in this build".into());
+        }
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+        {
+            return Err(Error::ProtocolError(format!(
+                "invalid cgroup name: {:?}",
+                name
+            )));
+        }
+        for (file, _) in limits {
+            validate_limit(file)?;
+        }
+        if !root.join("cgroup.controllers").exists() {
+            return Err(format!("cgroup: cgroup v2 is not mounted at {}", root.display()).into());
+        }
+
+        let parent = root.join(CGROUP_PARENT);
+        create_dir(&parent).map_err(|e| {
+            format!(
+                "cgroup: unable to create {} (root privileges required): {}",
+                parent.display(),
+                e
+            )
+        })?;
+
+        // Controllers must be enabled for the children of both the root and
+        // our parent for the limits to be available in the session cgroup.
+        for (file, _) in limits {
+            let controller = file.split('.').next().unwrap_or("");
+            for dir in &[root, parent.as_path()] {
+                write_file(
+                    &dir.join("cgroup.subtree_control"),
+                    &format!("+{}", controller),
+                )
+                .map_err(|e| {
+                    format!(
+                        "cgroup: unable to enable controller {} in {}: {}",
+                        controller,
+                        dir.display(),
+                        e
+                    )
+                })?;
+            }
+        }
+
+        let path = parent.join(name);
+        fs::create_dir(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => format!(
+                "cgroup: {} already exists, and may belong to another session",
+                path.display()
+            ),
+            _ => format!("cgroup: unable to create {}: {}", path.display(), e),
+        })?;
+        let cgroup = Cgroup {
+            path,
+            owner: getpid(),
+            removed: false,
+        };
+
+        // Do not leave a half-configured cgroup behind, which dropping it
+        // takes care of.
+        cgroup.apply_limits(limits)?;
+        Ok(cgroup)
+    }
+
+    fn apply_limits(&self, limits: &[(String, String)]) -> Result<(), Error> {
+        for (file, value) in limits {
+            write_file(&self.path.join(file), value)
+                .map_err(|e| format!("cgroup: unable to set {} to {}: {}", file, value, e))?;
+        }
+        Ok(())
+    }
+
+    /// Move a process into this cgroup.
+    pub fn add_process(&self, pid: Pid) -> io::Result<()> {
+        write_file(&self.path.join("cgroup.procs"), &pid.as_raw().to_string())
+    }
+
+    /// Remove this cgroup. This fails if the session left processes behind.
+    pub fn remove(mut self) -> io::Result<()> {
+        self.removed = true;
+        fs::remove_dir(&self.path)
+    }
+}
+
+impl Drop for Cgroup {
+    /// Remove a cgroup that was abandoned without being removed, such as when
+    /// the session could not be started after it was created. Forked children
+    /// inherit the cgroup, so only the process that created it removes it.
+    fn drop(&mut self) {
+        if !self.removed && getpid() == self.owner {
+            self.removed = true;
+            if let Err(e) = fs::remove_dir(&self.path) {
+                eprintln!("session: unable to remove session cgroup: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("greetd-cgroup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn support() {
+        let root = fake_root("support");
+        assert!(!supported(&root));
+        fs::write(root.join("cgroup.controllers"), "").unwrap();
+        assert_eq!(supported(&root), cfg!(feature = "cgroup"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "cgroup")]
+    fn create() {
+        let root = fake_root("create");
+        assert!(Cgroup::create(&root, "session", &[]).is_err());
+
+        fs::write(root.join("cgroup.controllers"), "memory pids").unwrap();
+        fs::write(root.join("cgroup.subtree_control"), "").unwrap();
+        fs::create_dir(root.join("greetd")).unwrap();
+        fs::write(root.join("greetd/cgroup.subtree_control"), "").unwrap();
+
+        // A fake hierarchy has no interface files in the session cgroup, so
+        // they are added once it has been created.
+        let limits = vec![("memory.max".to_string(), "2G".to_string())];
+        let cgroup = Cgroup::create(&root, "session", &[]).expect("unable to create cgroup");
+        fs::write(root.join("greetd/session/memory.max"), "max").unwrap();
+        fs::write(root.join("greetd/session/cgroup.procs"), "").unwrap();
+        cgroup.apply_limits(&limits).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("greetd/session/memory.max")).unwrap(),
+            "2G"
+        );
+
+        cgroup.add_process(Pid::from_raw(1234)).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("greetd/session/cgroup.procs")).unwrap(),
+            "1234"
+        );
+
+        // An existing cgroup is not taken over, nor removed.
+        assert!(Cgroup::create(&root, "session", &[]).is_err());
+        assert!(root.join("greetd/session").exists());
+        fs::remove_file(root.join("greetd/session/memory.max")).unwrap();
+        fs::remove_file(root.join("greetd/session/cgroup.procs")).unwrap();
+        cgroup.remove().unwrap();
+        assert!(!root.join("greetd/session").exists());
+
+        // A cgroup whose limits cannot be set is removed.
+        assert!(Cgroup::create(&root, "other", &limits).is_err());
+        assert_eq!(
+            fs::read_to_string(root.join("greetd/cgroup.subtree_control")).unwrap(),
+            "+memory"
+        );
+        assert!(!root.join("greetd/other").exists());
+
+        // So is one that is dropped, such as when the session could not be
+        // started.
+        drop(Cgroup::create(&root, "dropped", &[]).unwrap());
+        assert!(!root.join("greetd/dropped").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "cgroup")]
+    fn invalid_name() {
+        let root = fake_root("name");
+        fs::write(root.join("cgroup.controllers"), "").unwrap();
+        assert!(Cgroup::create(&root, "../escape", &[]).is_err());
+        assert!(Cgroup::create(&root, "a/b", &[]).is_err());
+        assert!(Cgroup::create(&root, "..", &[]).is_err());
+        assert!(!root.join("greetd").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "cgroup")]
+    fn invalid_limit() {
+        let root = fake_root("limit");
+        fs::write(root.join("cgroup.controllers"), "").unwrap();
+        for file in &[
+            "../../escape",
+            "memory/max",
+            "cgroup.procs",
+            "max",
+            ".max",
+            "memory.",
+        ] {
+            let limits = vec![(file.to_string(), "1".to_string())];
+            assert!(matches!(
+                Cgroup::create(&root, "session", &limits),
+                Err(Error::ProtocolError(_))
+            ));
+        }
+        assert!(!root.join("greetd").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::ffi::CStr;
+
+use nix::{
+    unistd::{self, Gid, Uid},
+    Error, Result,
+};
+
+use super::prctl::{prctl, PrctlOption};
+
+/// The operations that drop the privileges of the inner child to those of
+/// the user.
+pub trait PrivilegeDropper {
+    fn initgroups(&self, user: &CStr, gid: Gid) -> Result<()>;
+    fn setgid(&self, gid: Gid) -> Result<()>;
+    fn setuid(&self, uid: Uid) -> Result<()>;
+    fn set_pdeathsig(&self, signal: i32) -> Result<()>;
+}
+
+/// The operation that replaces the inner child with the session.
+pub trait Executor {
+    /// Execute the program, only returning if that failed.
+    fn execve(&self, path: &CStr, args: &[&CStr], env: &[&CStr]) -> Error;
+}
+
+/// The real thing.
+pub struct System;
+
+impl PrivilegeDropper for System {
+    fn initgroups(&self, user: &CStr, gid: Gid) -> Result<()> {
+        unistd::initgroups(user, gid)
+    }
+    fn setgid(&self, gid: Gid) -> Result<()> {
+        unistd::setgid(gid)
+    }
+    fn setuid(&self, uid: Uid) -> Result<()> {
+        unistd::setuid(uid)
+    }
+    fn set_pdeathsig(&self, signal: i32) -> Result<()> {
+        prctl(PrctlOption::SET_PDEATHSIG(signal))
+    }
+}
+
+impl Executor for System {
+    fn execve(&self, path: &CStr, args: &[&CStr], env: &[&CStr]) -> Error {
+        unistd::execve(path, args, env).unwrap_err()
+    }
+}
+
+/// Drop to the groups, GID and UID of the user, in that order, as each step
+/// needs the privileges that the next one gives up. The groups are those of
+/// the user with its own primary group, user_gid, even if the session is
+/// given another GID. The parent death signal is reset by the change of
+/// credentials, so it is set again afterwards.
+pub fn drop_privileges<P: PrivilegeDropper>(
+    ops: &P,
+    user: &CStr,
+    user_gid: Gid,
+    uid: Uid,
+    gid: Gid,
+    pdeathsig: i32,
+) -> std::result::Result<(), String> {
+    ops.initgroups(user, user_gid)
+        .map_err(|e| format!("unable to init groups: {}", e))?;
+    ops.setgid(gid)
+        .map_err(|e| format!("unable to set GID: {}", e))?;
+    ops.setuid(uid)
+        .map_err(|e| format!("unable to set UID: {}", e))?;
+    ops.set_pdeathsig(pdeathsig)
+        .map_err(|e| format!("unable to set death signal: {}", e))
+}
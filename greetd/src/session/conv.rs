@@ -1,5 +1,29 @@
-use super::worker::{AuthMessageType, ParentToSessionChild, SessionChildToParent};
-use crate::pam::converse::Converse;
+use std::{cell::RefCell, collections::VecDeque, os::unix::net::UnixDatagram};
+
+use super::worker::{
+    cancelled, AuthMessageType, ParentToSessionChild, PendingMessage, SessionChildToParent,
+};
+use crate::{error::Error, pam::converse::Converse};
+
+/// The maximum number of messages held for a polling parent. When full, the
+/// oldest message is discarded.
+const POLL_QUEUE_LEN: usize = 16;
+
+/// Interpret the response to a prompt. A missing response declines to answer,
+/// which aborts the conversation, while an empty response is submitted as an
+/// empty string, such as an empty password.
+fn answer(resp: Option<String>) -> Result<String, ()> {
+    resp.ok_or(())
+}
+
+/// Report a failure to receive a response. A parent that has disconnected
+/// has implicitly cancelled the login.
+fn cancel_on_error(e: Error) {
+    match e {
+        Error::PeerDisconnected => drop(cancelled(Some(e.to_string()))),
+        e => eprintln!("pam_conv: {}", e),
+    }
+}
 
 /// SessionConv is a PAM conversation implementation that forwards questions
 /// over a socket.
@@ -10,18 +34,21 @@ pub struct SessionConv<'a> {
 impl<'a> SessionConv<'a> {
     fn question(&self, msg: &str, style: AuthMessageType) -> Result<Option<String>, ()> {
         let msg = SessionChildToParent::PamMessage {
+            severity: style.severity(),
             style,
             msg: msg.to_string(),
         };
         msg.send(self.sock)
             .map_err(|e| eprintln!("pam_conv: {}", e))?;
 
-        let msg =
-            ParentToSessionChild::recv(self.sock).map_err(|e| eprintln!("pam_conv: {}", e))?;
+        let msg = ParentToSessionChild::recv(self.sock).map_err(cancel_on_error)?;
 
         match msg {
             ParentToSessionChild::PamResponse { resp, .. } => Ok(resp),
-            ParentToSessionChild::Cancel => Err(()),
+            ParentToSessionChild::Cancel { reason } => {
+                cancelled(reason);
+                Err(())
+            }
             _ => Err(()),
         }
     }
@@ -34,27 +61,261 @@ impl<'a> SessionConv<'a> {
 
 impl<'a> Converse for SessionConv<'a> {
     fn prompt_echo(&self, msg: &str) -> Result<String, ()> {
-        match self.question(msg, AuthMessageType::Visible) {
-            Ok(Some(response)) => Ok(response),
-            _ => Err(()),
-        }
+        answer(self.question(msg, AuthMessageType::Visible)?)
     }
     fn prompt_blind(&self, msg: &str) -> Result<String, ()> {
-        match self.question(msg, AuthMessageType::Secret) {
-            Ok(Some(response)) => Ok(response),
-            _ => Err(()),
-        }
+        answer(self.question(msg, AuthMessageType::Secret)?)
     }
     fn info(&self, msg: &str) -> Result<(), ()> {
-        match self.question(msg, AuthMessageType::Info) {
-            Ok(None) => Ok(()),
-            _ => Err(()),
+        self.question(msg, AuthMessageType::Info).map(drop)
+    }
+    fn error(&self, msg: &str) -> Result<(), ()> {
+        self.question(msg, AuthMessageType::Error).map(drop)
+    }
+}
+
+/// The messages a PollingConv holds for a polling parent. It outlives the
+/// conversation, so that whatever is left when the conversation ends can be
+/// delivered ahead of its result.
+#[derive(Default)]
+pub struct PollQueue(RefCell<VecDeque<PendingMessage>>);
+
+impl PollQueue {
+    /// Deliver the messages left in the queue, if any, to the parent, which
+    /// would otherwise never see them.
+    pub fn flush(&self, sock: &UnixDatagram) -> Result<(), Error> {
+        let pending: Vec<_> = self.0.borrow_mut().drain(..).collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        SessionChildToParent::PendingMessages(pending).send(sock)
+    }
+}
+
+/// PollingConv is a PAM conversation implementation that queues messages
+/// until the parent asks for them, for parents that poll rather than react to
+/// each message as it arrives. Informational messages are answered right
+/// away, while prompts block until the parent posts a response.
+pub struct PollingConv<'a> {
+    sock: &'a UnixDatagram,
+    queue: &'a RefCell<VecDeque<PendingMessage>>,
+}
+
+impl<'a> PollingConv<'a> {
+    fn push(&self, msg: &str, style: AuthMessageType) {
+        let mut queue = self.queue.borrow_mut();
+        if queue.len() == POLL_QUEUE_LEN {
+            queue.pop_front();
+        }
+        queue.push_back(PendingMessage {
+            severity: style.severity(),
+            style,
+            msg: msg.to_string(),
+        });
+    }
+
+    fn is_prompt(msg: &PendingMessage) -> bool {
+        msg.style.is_prompt()
+    }
+
+    fn question(&self, msg: &str, style: AuthMessageType) -> Result<Option<String>, ()> {
+        self.push(msg, style);
+        loop {
+            let msg = ParentToSessionChild::recv(self.sock).map_err(cancel_on_error)?;
+
+            match msg {
+                ParentToSessionChild::PollMessages => {
+                    // Delivered messages are discarded, but the prompt stays
+                    // pending until answered.
+                    let pending = self.queue.borrow().iter().cloned().collect();
+                    SessionChildToParent::PendingMessages(pending)
+                        .send(self.sock)
+                        .map_err(|e| eprintln!("pam_conv: {}", e))?;
+                    self.queue.borrow_mut().retain(Self::is_prompt);
+                }
+                ParentToSessionChild::PamResponse { resp, .. } => {
+                    self.queue.borrow_mut().retain(|m| !Self::is_prompt(m));
+                    return Ok(resp);
+                }
+                ParentToSessionChild::Cancel { reason } => {
+                    cancelled(reason);
+                    return Err(());
+                }
+                _ => return Err(()),
+            }
+        }
+    }
+
+    /// Create a new `PollingConv` handler, holding its messages in queue.
+    pub fn new(sock: &'a UnixDatagram, queue: &'a PollQueue) -> PollingConv<'a> {
+        PollingConv {
+            sock,
+            queue: &queue.0,
         }
     }
+}
+
+impl<'a> Converse for PollingConv<'a> {
+    fn prompt_echo(&self, msg: &str) -> Result<String, ()> {
+        answer(self.question(msg, AuthMessageType::Visible)?)
+    }
+    fn prompt_blind(&self, msg: &str) -> Result<String, ()> {
+        answer(self.question(msg, AuthMessageType::Secret)?)
+    }
+    fn info(&self, msg: &str) -> Result<(), ()> {
+        self.push(msg, AuthMessageType::Info);
+        Ok(())
+    }
     fn error(&self, msg: &str) -> Result<(), ()> {
-        match self.question(msg, AuthMessageType::Error) {
-            Ok(None) => Ok(()),
-            _ => Err(()),
+        self.push(msg, AuthMessageType::Error);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::worker::MAX_MESSAGE_SIZE;
+
+    fn send(sock: &UnixDatagram, msg: ParentToSessionChild) {
+        sock.send(&serde_json::to_vec(&msg).unwrap()).unwrap();
+    }
+
+    fn recv(sock: &UnixDatagram) -> SessionChildToParent {
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = sock.recv(&mut data[..]).unwrap();
+        serde_json::from_slice(&data[..len]).unwrap()
+    }
+
+    fn poll(sock: &UnixDatagram) -> Vec<String> {
+        send(sock, ParentToSessionChild::PollMessages);
+        match recv(sock) {
+            SessionChildToParent::PendingMessages(msgs) => {
+                msgs.into_iter().map(|m| m.msg).collect()
+            }
+            msg => panic!("expected PendingMessages, got: {:?}", msg),
+        }
+    }
+
+    fn respond(sock: &UnixDatagram, resp: Option<&str>) {
+        send(
+            sock,
+            ParentToSessionChild::PamResponse {
+                resp: resp.map(|r| r.to_string()),
+            },
+        );
+    }
+
+    #[test]
+    fn empty_response() {
+        let (worker, parent) = UnixDatagram::pair().unwrap();
+        let conv = SessionConv::new(&worker);
+
+        respond(&parent, Some(""));
+        assert_eq!(conv.prompt_blind("Password:"), Ok("".to_string()));
+
+        respond(&parent, None);
+        assert_eq!(conv.prompt_blind("Password:"), Err(()));
+
+        respond(&parent, Some(""));
+        assert_eq!(conv.prompt_echo("Username:"), Ok("".to_string()));
+
+        respond(&parent, None);
+        assert_eq!(conv.prompt_echo("Username:"), Err(()));
+
+        // Informational messages just need to be acknowledged.
+        respond(&parent, None);
+        assert_eq!(conv.info("Welcome"), Ok(()));
+
+        respond(&parent, Some(""));
+        assert_eq!(conv.error("Try again"), Ok(()));
+
+        send(&parent, ParentToSessionChild::Cancel { reason: None });
+        assert_eq!(conv.info("Welcome"), Err(()));
+    }
+
+    #[test]
+    fn polling_empty_response() {
+        let (worker, parent) = UnixDatagram::pair().unwrap();
+        let queue = PollQueue::default();
+        let conv = PollingConv::new(&worker, &queue);
+
+        respond(&parent, Some(""));
+        assert_eq!(conv.prompt_blind("Password:"), Ok("".to_string()));
+
+        respond(&parent, None);
+        assert_eq!(conv.prompt_blind("Password:"), Err(()));
+    }
+
+    #[test]
+    fn polling_conversation() {
+        let (worker, parent) = UnixDatagram::pair().unwrap();
+
+        let greeter = std::thread::spawn(move || {
+            assert_eq!(poll(&parent), vec!["Welcome", "Password:"]);
+            assert_eq!(poll(&parent), vec!["Password:"]);
+            send(
+                &parent,
+                ParentToSessionChild::PamResponse {
+                    resp: Some("hunter2".to_string()),
+                },
+            );
+        });
+
+        let queue = PollQueue::default();
+        let conv = PollingConv::new(&worker, &queue);
+        conv.info("Welcome").unwrap();
+        assert_eq!(conv.prompt_blind("Password:"), Ok("hunter2".to_string()));
+        assert!(conv.queue.borrow().is_empty());
+        greeter.join().unwrap();
+    }
+
+    #[test]
+    fn polling_queue_bounded() {
+        let (worker, _parent) = UnixDatagram::pair().unwrap();
+        let queue = PollQueue::default();
+        let conv = PollingConv::new(&worker, &queue);
+        for i in 0..POLL_QUEUE_LEN + 2 {
+            conv.info(&i.to_string()).unwrap();
         }
+        let queue = conv.queue.borrow();
+        assert_eq!(queue.len(), POLL_QUEUE_LEN);
+        assert_eq!(queue.front().unwrap().msg, "2");
+    }
+
+    #[test]
+    fn polling_trailing_messages() {
+        let (worker, parent) = UnixDatagram::pair().unwrap();
+        let queue = PollQueue::default();
+        let conv = PollingConv::new(&worker, &queue);
+
+        // Messages after the last prompt are only delivered by a flush.
+        respond(&parent, Some("hunter2"));
+        assert_eq!(conv.prompt_blind("Password:"), Ok("hunter2".to_string()));
+        conv.info("Last login: yesterday").unwrap();
+        conv.error("Password expires in 3 days").unwrap();
+        queue.flush(&worker).unwrap();
+        match recv(&parent) {
+            SessionChildToParent::PendingMessages(msgs) => {
+                let msgs: Vec<_> = msgs
+                    .into_iter()
+                    .map(|m| format!("{:?}: {}", m.style, m.msg))
+                    .collect();
+                assert_eq!(
+                    msgs,
+                    vec![
+                        "Info: Last login: yesterday",
+                        "Error: Password expires in 3 days"
+                    ]
+                );
+            }
+            msg => panic!("expected PendingMessages, got: {:?}", msg),
+        }
+
+        // An empty queue sends nothing.
+        queue.flush(&worker).unwrap();
+        parent.set_nonblocking(true).unwrap();
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        assert!(parent.recv(&mut data[..]).is_err());
     }
 }
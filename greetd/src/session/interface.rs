@@ -1,10 +1,11 @@
 use std::{
+    collections::VecDeque,
     ffi::CString,
-    os::unix::{io::AsRawFd, net::UnixDatagram},
+    os::unix::io::AsRawFd,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use nix::{
-    fcntl::{fcntl, FcntlArg, FdFlag},
     sys::signal::Signal,
     unistd::{execv, fork, ForkResult, Pid},
 };
@@ -13,7 +14,11 @@ use async_trait::async_trait;
 
 use tokio::net::UnixDatagram as TokioUnixDatagram;
 
-use super::worker::{AuthMessageType, ParentToSessionChild, SessionChildToParent, TerminalMode};
+use super::worker::{
+    check_sent, encode, set_cloexec, socket_pair, AuthMessageType, LoginOptions,
+    ParentToSessionChild, PendingMessage, ServicePolicy, SessionChildToParent, Severity,
+    TerminalMode, MAX_MESSAGE_SIZE, PROTOCOL_VERSION,
+};
 use crate::error::Error;
 
 #[async_trait]
@@ -29,19 +34,15 @@ trait AsyncSend {
 #[async_trait]
 impl AsyncSend for ParentToSessionChild {
     async fn send(&self, sock: &mut TokioUnixDatagram) -> Result<(), Error> {
-        let out =
-            serde_json::to_vec(self).map_err(|e| format!("unable to serialize message: {}", e))?;
-        sock.send(&out)
-            .await
-            .map_err(|e| format!("unable to send message: {}", e))?;
-        Ok(())
+        let out = encode(self)?;
+        check_sent(sock.send(&out).await, out.len())
     }
 }
 
 #[async_trait]
 impl AsyncRecv<SessionChildToParent> for SessionChildToParent {
     async fn recv(sock: &mut TokioUnixDatagram) -> Result<SessionChildToParent, Error> {
-        let mut data = [0; 10240];
+        let mut data = [0; MAX_MESSAGE_SIZE];
         let len = sock
             .recv(&mut data[..])
             .await
@@ -56,6 +57,10 @@ impl AsyncRecv<SessionChildToParent> for SessionChildToParent {
 pub struct SessionChild {
     pub task: Pid,
     pub sub_task: Pid,
+    /// The process group of the session process, as reported by the worker.
+    pub pgid: Pid,
+    /// When the session process was started, as reported by the worker.
+    pub started_at: SystemTime,
 }
 
 impl SessionChild {
@@ -76,9 +81,19 @@ impl SessionChild {
     }
 }
 
+/// How a session takes over its terminal when it is started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartMode {
+    /// Clear the terminal and switch to it.
+    Fresh,
+    /// Take over the terminal of a greeter that has exited, without clearing
+    /// it.
+    HandOff,
+}
+
 #[derive(Debug)]
 pub enum SessionState {
-    Question(AuthMessageType, String),
+    Question(AuthMessageType, Severity, String),
     Ready,
 }
 
@@ -87,33 +102,38 @@ pub struct Session {
     task: Pid,
     sock: TokioUnixDatagram,
     last_msg: Option<SessionChildToParent>,
+    pending: VecDeque<PendingMessage>,
+    /// Whether the worker holds its messages until polled, and may be
+    /// waiting for a PollMessages.
+    poll_next: bool,
 }
 
 impl Session {
-    /// Create a session started as an external process.
-    pub fn new_external() -> Result<Session, Error> {
+    /// Create a session started as an external process. The worker is given
+    /// the policy on its command line, ahead of any request.
+    pub fn new_external(policy: &ServicePolicy) -> Result<Session, Error> {
         // Pipe used to communicate the true PID of the final child.
-        let (parentfd, childfd) =
-            UnixDatagram::pair().map_err(|e| format!("could not create pipe: {}", e))?;
+        let (parentfd, childfd) = socket_pair()?;
 
         let raw_child = childfd.as_raw_fd();
-        let mut cur_flags =
-            unsafe { FdFlag::from_bits_unchecked(fcntl(raw_child, FcntlArg::F_GETFD)?) };
-        cur_flags.remove(FdFlag::FD_CLOEXEC);
-        fcntl(raw_child, FcntlArg::F_SETFD(cur_flags))?;
-
         let cur_exe = std::env::current_exe()?;
         let bin = CString::new(cur_exe.to_str().expect("unable to get current exe name"))?;
+        let policy = CString::new(serde_json::to_string(policy)?)?;
 
         let child = match fork().map_err(|e| format!("unable to fork: {}", e))? {
             ForkResult::Parent { child, .. } => child,
             ForkResult::Child => {
+                // The socket is made inheritable only in the child, so that it
+                // cannot leak into workers forked concurrently.
+                set_cloexec(raw_child, false).expect("unable to pass socket to worker");
                 execv(
                     &bin,
                     &[
                         &bin,
                         &CString::new("--session-worker").unwrap(),
                         &CString::new(format!("{}", raw_child as usize)).unwrap(),
+                        &CString::new("--service-policy").unwrap(),
+                        &policy,
                     ],
                 )
                 .expect("unable to exec");
@@ -126,10 +146,13 @@ impl Session {
             task: child,
             sock: TokioUnixDatagram::from_std(parentfd)?,
             last_msg: None,
+            pending: VecDeque::new(),
+            poll_next: false,
         })
     }
 
     /// Initiates the session, which will cause authentication to begin.
+    #[allow(clippy::too_many_arguments)]
     pub async fn initiate(
         &mut self,
         service: &str,
@@ -138,7 +161,24 @@ impl Session {
         authenticate: bool,
         term_mode: &TerminalMode,
         source_profile: bool,
+        options: &LoginOptions,
     ) -> Result<(), Error> {
+        // Wait for the worker to announce itself.
+        match SessionChildToParent::recv(&mut self.sock).await? {
+            SessionChildToParent::Ready { version, .. } if version == PROTOCOL_VERSION => (),
+            SessionChildToParent::Ready { version, .. } => {
+                return Err(Error::ProtocolError(format!(
+                    "session worker speaks protocol version {}, expected {}",
+                    version, PROTOCOL_VERSION
+                )))
+            }
+            SessionChildToParent::Error(e) => return Err(e),
+            msg => panic!(
+                "expected Ready or Error from session worker, got: {:?}",
+                msg
+            ),
+        }
+
         let msg = ParentToSessionChild::InitiateLogin {
             service: service.to_string(),
             class: class.to_string(),
@@ -146,44 +186,102 @@ impl Session {
             authenticate,
             tty: term_mode.clone(),
             source_profile,
+            options: Box::new(options.clone()),
         };
         msg.send(&mut self.sock).await?;
+        self.poll_next = options.poll_conversation;
         Ok(())
     }
 
-    /// Return the current state of this session.
+    /// Return the current state of this session. Messages that the worker
+    /// delivered in bulk are presented one at a time, ahead of whatever it
+    /// sent next. A worker that holds its messages is polled for them, which
+    /// it ignores if its conversation has already ended.
     pub async fn get_state(&mut self) -> Result<SessionState, Error> {
-        let msg = match self.last_msg.take() {
-            Some(msg) => msg,
-            None => SessionChildToParent::recv(&mut self.sock).await?,
+        let msg = loop {
+            if let Some(m) = self.pending.front() {
+                return Ok(SessionState::Question(
+                    m.style.clone(),
+                    m.severity,
+                    m.msg.clone(),
+                ));
+            }
+            let msg = match self.last_msg.take() {
+                Some(msg) => msg,
+                None => {
+                    if self.poll_next {
+                        // A worker that has already exited cannot be polled,
+                        // but what it sent before it went is still to be
+                        // received, so that is what reports the outcome.
+                        self.poll_next = false;
+                        let _ = ParentToSessionChild::PollMessages
+                            .send(&mut self.sock)
+                            .await;
+                    }
+                    SessionChildToParent::recv(&mut self.sock).await?
+                }
+            };
+            match msg {
+                SessionChildToParent::Authenticated { cached } => {
+                    if cached {
+                        eprintln!("user authenticated by cached service");
+                    }
+                    continue;
+                }
+                SessionChildToParent::PendingMessages(msgs) => {
+                    self.pending.extend(msgs);
+                    continue;
+                }
+                SessionChildToParent::Timings(timings) => {
+                    eprintln!("session timings: {}", timings);
+                    continue;
+                }
+                msg => break msg,
+            }
         };
 
         self.last_msg = Some(msg.clone());
 
         match msg {
-            SessionChildToParent::PamMessage { style, msg } => {
-                Ok(SessionState::Question(style, msg))
-            }
+            SessionChildToParent::PamMessage {
+                style,
+                severity,
+                msg,
+            } => Ok(SessionState::Question(style, severity, msg)),
             SessionChildToParent::Success => Ok(SessionState::Ready),
             SessionChildToParent::Error(e) => Err(e),
             msg => panic!(
-                "expected PamMessage, Success or Error from session worker, got: {:?}",
+                "expected PamMessage, PendingMessages, Success or Error from session worker, got: {:?}",
                 msg
             ),
         }
     }
 
-    /// Cancel the session.
-    pub async fn cancel(&mut self) -> Result<(), Error> {
+    /// Cancel the session, optionally explaining why for the logs.
+    pub async fn cancel(&mut self, reason: Option<&str>) -> Result<(), Error> {
         self.last_msg = None;
-        ParentToSessionChild::Cancel.send(&mut self.sock).await?;
+        self.pending.clear();
+        self.poll_next = false;
+        ParentToSessionChild::Cancel {
+            reason: reason.map(|r| r.to_string()),
+        }
+        .send(&mut self.sock)
+        .await?;
         Ok(())
     }
 
     /// Send a response to an authentication question, or None to cancel the
     /// authentication attempt.
     pub async fn post_response(&mut self, answer: Option<String>) -> Result<(), Error> {
-        self.last_msg = None;
+        match self.pending.pop_front() {
+            // Informational messages delivered in bulk have already been
+            // answered by the worker.
+            Some(m) if !m.style.is_prompt() => return Ok(()),
+            // The worker waits for the answer to the prompt it ended the
+            // bulk delivery with, and holds whatever comes next until polled.
+            Some(_) => self.poll_next = true,
+            None => self.last_msg = None,
+        }
         ParentToSessionChild::PamResponse { resp: answer }
             .send(&mut self.sock)
             .await?;
@@ -197,7 +295,15 @@ impl Session {
         let msg = ParentToSessionChild::Args { cmd };
         msg.send(&mut self.sock).await?;
 
-        let msg = SessionChildToParent::recv(&mut self.sock).await?;
+        let msg = loop {
+            match SessionChildToParent::recv(&mut self.sock).await? {
+                SessionChildToParent::Timings(timings) => {
+                    eprintln!("session timings: {}", timings);
+                    continue;
+                }
+                msg => break msg,
+            }
+        };
 
         self.last_msg = Some(msg.clone());
 
@@ -212,18 +318,50 @@ impl Session {
     }
 
     ///
-    /// Start the session.
+    /// Start the session. A session handed off from a greeter takes over the
+    /// terminal of the greeter, which must have exited.
     ///
-    pub async fn start(&mut self) -> Result<SessionChild, Error> {
-        let msg = ParentToSessionChild::Start;
+    pub async fn start(&mut self, mode: StartMode) -> Result<SessionChild, Error> {
+        let msg = match mode {
+            StartMode::Fresh => ParentToSessionChild::Start,
+            StartMode::HandOff => ParentToSessionChild::HandOff,
+        };
         msg.send(&mut self.sock).await?;
 
+        let mut started = None;
         let sub_task = loop {
             match SessionChildToParent::recv(&mut self.sock).await? {
                 SessionChildToParent::Error(e) => return Err(e),
                 SessionChildToParent::FinalChildPid(raw_pid) => {
                     break Pid::from_raw(raw_pid as i32)
                 }
+                SessionChildToParent::SessionStarted {
+                    pgid, started_at, ..
+                } => {
+                    started = Some((
+                        Pid::from_raw(pgid as i32),
+                        UNIX_EPOCH + Duration::from_millis(started_at),
+                    ));
+                    continue;
+                }
+                SessionChildToParent::PendingMessages(msgs) => {
+                    // pam_conv after start, nobody to show them to
+                    for m in msgs {
+                        eprintln!("session: {:?}: {}", m.style, m.msg);
+                    }
+                    continue;
+                }
+                SessionChildToParent::Timings(timings) => {
+                    eprintln!("session timings: {}", timings);
+                    continue;
+                }
+                SessionChildToParent::FallbackStarted { cmd } => {
+                    eprintln!(
+                        "session command could not be executed, started fallback: {:?}",
+                        cmd
+                    );
+                    continue;
+                }
                 SessionChildToParent::PamMessage { .. } => {
                     // pam_conv after start, ignore
                     ParentToSessionChild::PamResponse { resp: None }
@@ -240,9 +378,103 @@ impl Session {
 
         self.sock.shutdown(std::net::Shutdown::Both)?;
 
+        // SessionStarted always precedes FinalChildPid.
+        let (pgid, started_at) = started.ok_or_else(|| {
+            Error::ProtocolError("session worker did not report the session start".to_string())
+        })?;
         Ok(SessionChild {
             task: self.task,
             sub_task,
+            pgid,
+            started_at,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pam::converse::Converse,
+        session::conv::{PollQueue, PollingConv},
+    };
+    use std::thread;
+
+    #[tokio::test]
+    async fn polling_conversation() {
+        let (parent, worker_sock) = socket_pair().unwrap();
+        // Stands in for a worker whose PAM stack prompts twice, with
+        // messages around the prompts.
+        let worker = thread::spawn(move || -> Result<(String, String), Error> {
+            SessionChildToParent::Ready {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![],
+            }
+            .send(&worker_sock)?;
+            match ParentToSessionChild::recv(&worker_sock)? {
+                ParentToSessionChild::InitiateLogin { .. } => (),
+                msg => panic!("expected InitiateLogin, got: {:?}", msg),
+            }
+            let queue = PollQueue::default();
+            let conv = PollingConv::new(&worker_sock, &queue);
+            conv.info("Welcome").unwrap();
+            let password = conv.prompt_blind("Password:").unwrap();
+            conv.error("Password expired").unwrap();
+            let token = conv.prompt_echo("Token:").unwrap();
+            conv.info("Done").unwrap();
+            queue.flush(&worker_sock)?;
+            SessionChildToParent::Success.send(&worker_sock)?;
+            Ok((password, token))
+        });
+
+        let mut session = Session {
+            task: Pid::from_raw(0),
+            sock: TokioUnixDatagram::from_std(parent).unwrap(),
+            last_msg: None,
+            pending: VecDeque::new(),
+            poll_next: false,
+        };
+        let options = LoginOptions {
+            poll_conversation: true,
+            ..Default::default()
+        };
+        session
+            .initiate(
+                "greetd",
+                "user",
+                "john",
+                true,
+                &TerminalMode::Stdin,
+                false,
+                &options,
+            )
+            .await
+            .unwrap();
+
+        let mut questions = vec![];
+        while let SessionState::Question(style, severity, msg) = session.get_state().await.unwrap()
+        {
+            let answer = match style {
+                AuthMessageType::Secret => Some("hunter2".to_string()),
+                AuthMessageType::Visible => Some("123456".to_string()),
+                _ => None,
+            };
+            questions.push(format!("{:?} {:?}: {}", severity, style, msg));
+            session.post_response(answer).await.unwrap();
+        }
+        assert_eq!(
+            questions,
+            vec![
+                "Info Info: Welcome",
+                "Notice Secret: Password:",
+                "Error Error: Password expired",
+                "Notice Visible: Token:",
+                "Info Info: Done",
+            ]
+        );
+        assert_eq!(
+            worker.join().unwrap().unwrap(),
+            ("hunter2".to_string(), "123456".to_string())
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::ptr;
+
+use nix::{errno::Errno, Result};
+
+pub const KEYCTL_JOIN_SESSION_KEYRING: libc::c_long = 1;
+
+/// Create a new anonymous session keyring and join it, as pam_keyinit does.
+/// Returns None if the kernel does not support keyrings.
+pub fn join_session_keyring() -> Result<Option<i32>> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            KEYCTL_JOIN_SESSION_KEYRING,
+            ptr::null::<libc::c_char>(),
+        )
+    };
+    match Errno::result(res) {
+        Ok(id) => Ok(Some(id as i32)),
+        Err(nix::Error::Sys(Errno::ENOSYS)) | Err(nix::Error::Sys(Errno::EOPNOTSUPP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
+    };
+
+    const KEYCTL_GET_KEYRING_ID: libc::c_long = 0;
+    const KEY_SPEC_SESSION_KEYRING: libc::c_long = -3;
+
+    fn session_keyring() -> libc::c_long {
+        unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_GET_KEYRING_ID,
+                KEY_SPEC_SESSION_KEYRING,
+                1,
+            )
+        }
+    }
+
+    #[test]
+    fn new_session_keyring() {
+        // Joining a keyring affects the whole process, so do it in a child.
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let before = session_keyring();
+                let code = match join_session_keyring() {
+                    Ok(Some(id)) if id as libc::c_long != before => {
+                        if session_keyring() == id as libc::c_long {
+                            0
+                        } else {
+                            1
+                        }
+                    }
+                    Ok(Some(_)) => 2,
+                    Ok(None) => 0,
+                    Err(_) => 3,
+                };
+                unsafe { libc::_exit(code) };
+            }
+        }
+    }
+}
@@ -0,0 +1,2552 @@
+//! Direct control of a login, for embedding the session worker logic.
+//!
+//! A login goes through the same steps as the InitiateLogin, Args and Start
+//! messages of the session worker protocol, but as plain calls:
+//!
+//! ```no_run
+//! # use greetd::{
+//! #     error::Error,
+//! #     pam::converse::Converse,
+//! #     session::{
+//! #         login::Login,
+//! #         worker::{LoginOptions, TerminalMode},
+//! #     },
+//! # };
+//! # struct Password;
+//! # impl Converse for Password {
+//! #     fn prompt_echo(&self, _msg: &str) -> Result<String, ()> { Err(()) }
+//! #     fn prompt_blind(&self, _msg: &str) -> Result<String, ()> { Ok("hunter2".to_string()) }
+//! #     fn info(&self, _msg: &str) -> Result<(), ()> { Ok(()) }
+//! #     fn error(&self, _msg: &str) -> Result<(), ()> { Ok(()) }
+//! # }
+//! # fn main() -> Result<(), Error> {
+//! let conv = Box::pin(Password);
+//! let mut login = Login::start("greetd", "user", "john", conv, true, LoginOptions::default())?;
+//! login.authenticate()?;
+//! login.authorize()?;
+//! login.set_args(vec!["sway".to_string()]);
+//! let session = login.run(TerminalMode::Stdin)?;
+//! println!("session started with pid {}", session.pid());
+//! session.wait()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The PAM conversation is provided up front, as PAM needs it from the start
+//! of the transaction. Running the session makes the calling process a
+//! session leader and has it wait for the session to end, so it should be
+//! done in a process dedicated to the session.
+
+use std::{
+    cell::Cell,
+    env,
+    ffi::{CStr, CString, OsStr, OsString},
+    fs::File,
+    io::{self, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::MetadataExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::{Component, Path},
+    pin::Pin,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    sched::{sched_getaffinity, sched_setaffinity, CpuSet},
+    sys::{
+        signal::{signal, SigHandler, Signal},
+        stat::Mode,
+        wait::waitpid,
+    },
+    unistd::{close, dup2, fork, getpid, pipe2, setsid, write, ForkResult, Gid, Pid, Uid},
+};
+use pam_sys::{PamFlag, PamItemType, PamReturnCode};
+use users::{
+    os::unix::{GroupExt, UserExt},
+    Group, User,
+};
+
+use super::{
+    cgroup::{Cgroup, CGROUP_ROOT},
+    child::{drop_privileges, Executor, PrivilegeDropper, System},
+    keyring::join_session_keyring,
+    loginuid::{set_loginuid, LOGINUID_PATH},
+    prctl::{prctl, PrctlOption, PROCESS_NAME_MAX},
+    ttyowner::TtyOwner,
+    userenv::load_as_user,
+    utmp::{self, UTMP_PATH, WTMP_PATH},
+    worker::{LoginOptions, TerminalMode, Timings},
+};
+use crate::{
+    error::Error,
+    pam::{converse::Converse, env::PamEnv, session::PamSession, PamError},
+    terminal::{self, grace::SwitchGrace, setup::setup_session_terminal, Terminal},
+};
+
+/// Assemble the environment variables that are passed to PAM before
+/// open_session, and which thereby end up in the session environment. The
+/// greetd socket is only passed on when there is one, as the login may be
+/// run by something other than the greetd daemon.
+fn prepared_env(class: &str, greetd_sock: Option<&str>, options: &LoginOptions) -> Vec<String> {
+    let mut env = vec![
+        "XDG_SEAT=seat0".to_string(),
+        format!("XDG_SESSION_CLASS={}", class),
+        format!(
+            "TERM={}",
+            env::var("TERM").unwrap_or_else(|_| "linux".to_string())
+        ),
+    ];
+
+    if let Some(sock) = greetd_sock {
+        env.push(format!("GREETD_SOCK={}", sock));
+    }
+
+    if let Some(desktop) = &options.session_desktop {
+        env.push(format!("XDG_SESSION_DESKTOP={}", desktop));
+    }
+
+    // The keyboard layout chosen in the greeter, as picked up by Wayland
+    // compositors through libxkbcommon.
+    for (key, value) in &[
+        ("XKB_DEFAULT_LAYOUT", &options.xkb_layout),
+        ("XKB_DEFAULT_VARIANT", &options.xkb_variant),
+        ("XKB_DEFAULT_OPTIONS", &options.xkb_options),
+    ] {
+        if let Some(value) = value {
+            env.push(format!("{}={}", key, value));
+        }
+    }
+
+    env
+}
+
+/// Assemble the environment variables describing the user, which are passed
+/// to PAM once the user has been looked up. The values come from the user
+/// database, and need not be valid UTF-8.
+fn user_env(username: &OsStr, home: &OsStr, shell: &OsStr, pwd: &OsStr) -> Vec<OsString> {
+    [
+        ("USER", username),
+        ("LOGNAME", username),
+        ("HOME", home),
+        ("SHELL", shell),
+        ("PWD", pwd),
+    ]
+    .iter()
+    .map(|(key, value)| {
+        let mut entry = OsString::from(key);
+        entry.push("=");
+        entry.push(value);
+        entry
+    })
+    .collect()
+}
+
+/// Look up the user, retrying as requested if the user does not exist. PAM
+/// modules that provision users on login may not have finished creating the
+/// user by the time the session is opened.
+fn lookup_user<F>(name: &str, retries: u32, delay_ms: u64, mut lookup: F) -> Result<User, Error>
+where
+    F: FnMut(&str) -> Option<User>,
+{
+    let mut attempt = 0;
+    loop {
+        if let Some(user) = lookup(name) {
+            return Ok(user);
+        }
+        if attempt >= retries {
+            return Err(format!("user {} does not exist", name).into());
+        }
+        attempt += 1;
+        eprintln!(
+            "session: user {} does not exist, retrying ({}/{})",
+            name, attempt, retries
+        );
+        thread::sleep(Duration::from_millis(delay_ms));
+    }
+}
+
+/// Resolve the primary group of the session, which is that of the user unless
+/// another group is requested. The user must be a member of the requested
+/// group, so that no group is granted that the user did not already have.
+fn session_group<F>(user: &User, group: Option<&str>, lookup: F) -> Result<Gid, Error>
+where
+    F: FnOnce(&str) -> Option<Group>,
+{
+    let name = match group {
+        Some(name) => name,
+        None => return Ok(Gid::from_raw(user.primary_group_id())),
+    };
+    let group = lookup(name).ok_or_else(|| format!("group {} does not exist", name))?;
+    let member = group.gid() == user.primary_group_id()
+        || group.members().iter().any(|m| m.as_os_str() == user.name());
+    if !member {
+        return Err(format!(
+            "user {} is not a member of group {}",
+            user.name().to_string_lossy(),
+            name
+        )
+        .into());
+    }
+    Ok(Gid::from_raw(group.gid()))
+}
+
+/// The prefix of variables removed from the session environment by default.
+const ENV_SCRUB_PREFIX: &str = "GREETD_";
+/// The variables kept despite matching the prefix, which sessions need to
+/// talk to greetd.
+const ENV_SCRUB_KEEP: &[&str] = &["GREETD_SOCK"];
+
+/// Remove the variables internal to greetd and its greeters from the session
+/// environment, except for those that are to be kept.
+fn scrub_env(env: &mut PamEnv, options: &LoginOptions) {
+    let prefix = options
+        .env_scrub_prefix
+        .as_deref()
+        .unwrap_or(ENV_SCRUB_PREFIX);
+    if prefix.is_empty() {
+        return;
+    }
+    env.retain(|key, _| {
+        let key = key.as_bytes();
+        !key.starts_with(prefix.as_bytes())
+            || ENV_SCRUB_KEEP.iter().any(|k| k.as_bytes() == key)
+            || options.env_scrub_keep.iter().any(|k| k.as_bytes() == key)
+    });
+}
+
+/// Forward the listed variables from our own environment to the session
+/// environment, unless they are already set there.
+fn forward_env(env: &mut PamEnv, names: &[String]) -> Result<(), Error> {
+    for name in names {
+        if env.get(name).is_some() {
+            continue;
+        }
+        if let Some(value) = env::var_os(name) {
+            env.set(name, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// The profiles sourced before the session command, in order.
+const PROFILES: [&str; 2] = ["/etc/profile", "$HOME/.profile"];
+
+/// Generate the shell command that runs the session, optionally sourcing the
+/// profiles first. In strict mode, the status of each profile is checked
+/// right after it is sourced, and a profile returning non-zero aborts the
+/// session with an error on the terminal.
+fn session_command(cmd: &[String], source_profile: bool, profile_strict: bool) -> String {
+    let mut command = String::new();
+    if source_profile {
+        for profile in PROFILES.iter() {
+            if profile_strict {
+                command.push_str(&format!(
+                    "if [ -f \"{0}\" ]; then . \"{0}\"; s=$?; if [ $s -ne 0 ]; then echo \"greetd: {0} failed with status $s\" >&2; exit 1; fi; fi; ",
+                    profile
+                ));
+            } else {
+                command.push_str(&format!("[ -f \"{0}\" ] && . \"{0}\"; ", profile));
+            }
+        }
+    }
+    command.push_str("exec ");
+    command.push_str(&cmd.join(" "));
+    command
+}
+
+/// The program that the session command starts, if it can be told from the
+/// command line. A command starting with anything but a plain word, such as
+/// a variable assignment or a shell construct, is left to the shell.
+fn session_program(cmd: &[String]) -> Option<String> {
+    let line = cmd.join(" ");
+    let word = line.split_whitespace().next()?;
+    let plain = word
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/._-+".contains(c));
+    if plain {
+        Some(word.to_string())
+    } else {
+        None
+    }
+}
+
+/// Whether the program of the session command can be executed, searching the
+/// specified PATH as the shell would. The shell that runs the session cannot
+/// report a missing program other than by its exit status, so this is checked
+/// before executing it. As the profiles have not been sourced at this point,
+/// programs only found through a PATH set by the profiles are missed.
+fn program_found(cmd: &[String], path: Option<&OsStr>) -> bool {
+    let program = match session_program(cmd) {
+        Some(program) => program,
+        None => return true,
+    };
+    let executable = |path: &Path| {
+        let cpath = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(cpath) => cpath,
+            Err(_) => return false,
+        };
+        path.is_file() && unsafe { libc::access(cpath.as_ptr(), libc::X_OK) } == 0
+    };
+    if program.contains('/') {
+        return executable(Path::new(&program));
+    }
+    let path = path.unwrap_or_else(|| OsStr::new("/usr/local/bin:/usr/bin:/bin"));
+    env::split_paths(path).any(|dir| executable(&dir.join(&program)))
+}
+
+/// Change the working directory to the home directory, falling back to the
+/// root directory if the home directory cannot be entered.
+fn enter_home(home: &OsStr) -> Result<&OsStr, Error> {
+    match env::set_current_dir(home) {
+        Ok(_) => Ok(home),
+        Err(_) => {
+            env::set_current_dir("/")
+                .map_err(|e| format!("unable to set working directory: {}", e))?;
+            Ok(OsStr::new("/"))
+        }
+    }
+}
+
+/// Sent by the inner child through the exec status pipe before it attempts
+/// the fallback command.
+const EXEC_STATUS_FALLBACK: u8 = b'F';
+/// Sent by the inner child through the exec status pipe when it gives up.
+const EXEC_STATUS_FAILED: u8 = b'E';
+/// Sent by the inner child through the exec status pipe if it could not set
+/// its CPU affinity, in which case the session is started regardless.
+const EXEC_STATUS_AFFINITY: u8 = b'A';
+
+/// Execute the session command, or the fallback command if the session
+/// command could not be found or is not executable, which includes the
+/// program run by it not having been found. This only returns if neither
+/// could be executed, in which case the inner child should exit. Progress is
+/// reported through the status file descriptor, which is closed on a
+/// successful exec.
+fn exec_session<E: Executor>(
+    exec: &E,
+    args: &[CString],
+    found: bool,
+    fallback: Option<&[String]>,
+    env: &[&CStr],
+    status: RawFd,
+) -> nix::Error {
+    let cargs: Vec<&CStr> = args.iter().map(|a| a.as_c_str()).collect();
+    let mut err = if found {
+        exec.execve(cargs[0], &cargs, env)
+    } else {
+        nix::Error::Sys(Errno::ENOENT)
+    };
+
+    if let Some(fallback) = fallback {
+        if matches!(err, nix::Error::Sys(Errno::ENOENT | Errno::EACCES)) {
+            let _ = write(status, &[EXEC_STATUS_FALLBACK]);
+            match fallback
+                .iter()
+                .map(|a| CString::new(a.as_str()))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(args) => {
+                    let cargs: Vec<&CStr> = args.iter().map(|a| a.as_c_str()).collect();
+                    err = exec.execve(cargs[0], &cargs, env);
+                }
+                Err(_) => err = nix::Error::InvalidPath,
+            }
+        }
+    }
+
+    let _ = write(status, &[EXEC_STATUS_FAILED]);
+    err
+}
+
+/// Restore the default disposition of SIGPIPE. The Rust runtime ignores
+/// SIGPIPE, which would otherwise be inherited by the session, leaving
+/// programs that expect to be terminated by writes to closed pipes to spin on
+/// EPIPE instead.
+fn reset_sigpipe() -> nix::Result<()> {
+    unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) }.map(drop)
+}
+
+/// Build the CPU affinity mask of the session, checking the CPUs against the
+/// mask greetd itself may run on. CPU numbers need not be contiguous, as CPUs
+/// may be offline or excluded by a cpuset.
+fn cpu_set(cpus: &[usize], allowed: &CpuSet) -> Result<CpuSet, Error> {
+    if cpus.is_empty() {
+        return Err(Error::ProtocolError("empty CPU affinity".to_string()));
+    }
+    let invalid: Vec<String> = cpus
+        .iter()
+        .filter(|&&cpu| !allowed.is_set(cpu).unwrap_or(false))
+        .map(|cpu| cpu.to_string())
+        .collect();
+    if !invalid.is_empty() {
+        return Err(Error::ProtocolError(format!(
+            "invalid CPUs in affinity: {} (not available to greetd)",
+            invalid.join(", ")
+        )));
+    }
+
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        set.set(cpu)?;
+    }
+    Ok(set)
+}
+
+/// Open a file relative to a directory, without following a symlink in its
+/// place.
+fn open_at(dir: &File, name: &OsStr, flags: libc::c_int, mode: libc::mode_t) -> io::Result<File> {
+    let name = CString::new(name.as_bytes())?;
+    match unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// Open the directory holding a file, one component at a time, so that no
+/// symlink is followed anywhere along the path. The directory is returned
+/// along with the name of the file in it.
+fn open_parent(path: &Path) -> io::Result<(File, &OsStr)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid file path");
+    let name = path.file_name().ok_or_else(invalid)?;
+    let parent = path.parent().ok_or_else(invalid)?;
+    let mut dir = File::open(if path.is_absolute() { "/" } else { "." })?;
+    for component in parent.components() {
+        match component {
+            Component::RootDir | Component::CurDir => (),
+            Component::Normal(next) => {
+                dir = open_at(&dir, next, libc::O_RDONLY | libc::O_DIRECTORY, 0)?
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok((dir, name))
+}
+
+/// Open a log file for the session, owned by the user. As the file may be in
+/// a directory writable by the user, symlinks are not followed in any part of
+/// the path, and an existing file is only used if it is a regular file of the
+/// user with no other links, so that no other file is truncated and handed to
+/// the user.
+fn open_log(path: &Path, append: bool, uid: Uid, gid: Gid) -> io::Result<File> {
+    let (dir, name) = open_parent(path)?;
+    let mut flags = libc::O_WRONLY | libc::O_NONBLOCK;
+    if append {
+        flags |= libc::O_APPEND;
+    }
+    let file = match open_at(&dir, name, flags | libc::O_CREAT | libc::O_EXCL, 0o600) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let file = open_at(&dir, name, flags, 0)?;
+            let meta = file.metadata()?;
+            let refuse = |reason: &str| Err(io::Error::other(reason));
+            if !meta.file_type().is_file() {
+                return refuse("not a regular file");
+            }
+            if meta.nlink() != 1 {
+                return refuse("file has other links");
+            }
+            if meta.uid() != uid.as_raw() {
+                return refuse("file is owned by another user");
+            }
+            file
+        }
+        Err(e) => return Err(e),
+    };
+    if !append {
+        file.set_len(0)?;
+    }
+    if unsafe { libc::fchown(file.as_raw_fd(), uid.as_raw(), gid.as_raw()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Connect stdout and stderr to the log file, and stdin to /dev/null.
+fn connect_log(log_file: &File) -> nix::Result<()> {
+    let null = open("/dev/null", OFlag::O_RDONLY, Mode::empty())?;
+    dup2(null, 0)?;
+    close(null)?;
+    dup2(log_file.as_raw_fd(), 1)?;
+    dup2(log_file.as_raw_fd(), 2)?;
+    Ok(())
+}
+
+/// Read the exec status reported by the inner child until it either execs or
+/// exits.
+fn read_exec_status(status: RawFd) -> io::Result<Vec<u8>> {
+    let mut file = unsafe { File::from_raw_fd(status) };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Ensure that a PAM service name refers to a file directly within the PAM
+/// configuration directory, as the name is provided by the greeter.
+fn validate_service(service: &str) -> Result<(), Error> {
+    let valid_chars = service
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    if service.is_empty() || !valid_chars || service.starts_with('.') {
+        return Err(Error::ProtocolError(format!(
+            "invalid PAM service name: {:?}",
+            service
+        )));
+    }
+    Ok(())
+}
+
+/// Expand a process name template, truncating the result to the limit of
+/// PR_SET_NAME without splitting characters.
+fn process_name(template: &str, username: &str) -> CString {
+    let mut name = template.replace("{user}", username).replace('\0', "");
+    let mut len = name.len().min(PROCESS_NAME_MAX);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    name.truncate(len);
+    CString::new(name).expect("process name contains NUL")
+}
+
+/// Whether an open_session failure is likely to be caused by a service that
+/// has not yet become available, such as logind or D-Bus early at boot.
+fn is_transient_session_error(rc: PamReturnCode) -> bool {
+    matches!(rc, PamReturnCode::SYSTEM_ERR | PamReturnCode::SESSION_ERR)
+}
+
+/// The delay before the specified open_session retry, starting at 1.
+fn open_session_backoff(base_ms: u64, attempt: u32) -> Duration {
+    let factor = 1u64 << (attempt.saturating_sub(1)).min(16);
+    Duration::from_millis(base_ms.saturating_mul(factor))
+}
+
+/// Whether the failure of the cached service means that the user should be
+/// prompted instead. The cached service is run without a conversation, so a
+/// module that wants to prompt fails with a conversation error.
+fn is_cache_miss(rc: PamReturnCode) -> bool {
+    matches!(
+        rc,
+        PamReturnCode::AUTH_ERR
+            | PamReturnCode::CRED_INSUFFICIENT
+            | PamReturnCode::AUTHINFO_UNAVAIL
+            | PamReturnCode::CONV_ERR
+    )
+}
+
+/// The conversation of the greeter, shared by the transaction of the login
+/// and that of the cached service. The cached service must authenticate
+/// without prompting, so its transaction refuses prompts and only logs
+/// messages until the login continues in it.
+struct SharedConv<'a> {
+    conv: Rc<Pin<Box<dyn Converse + 'a>>>,
+    prompting: Rc<Cell<bool>>,
+}
+
+impl Converse for SharedConv<'_> {
+    fn prompt_echo(&self, msg: &str) -> Result<String, ()> {
+        if !self.prompting.get() {
+            return Err(());
+        }
+        self.conv.prompt_echo(msg)
+    }
+    fn prompt_blind(&self, msg: &str) -> Result<String, ()> {
+        if !self.prompting.get() {
+            return Err(());
+        }
+        self.conv.prompt_blind(msg)
+    }
+    fn info(&self, msg: &str) -> Result<(), ()> {
+        if !self.prompting.get() {
+            eprintln!("session: cached authentication: {}", msg);
+            return Ok(());
+        }
+        self.conv.info(msg)
+    }
+    fn error(&self, msg: &str) -> Result<(), ()> {
+        if !self.prompting.get() {
+            eprintln!("session: cached authentication: {}", msg);
+            return Ok(());
+        }
+        self.conv.error(msg)
+    }
+}
+
+/// How the user was authenticated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthPath {
+    /// By the cached service, without prompting.
+    Cached,
+    /// Through the conversation of the login.
+    Interactive,
+}
+
+/// The PAM calls of authentication, which may involve the transaction of
+/// the cached service as well as that of the login.
+trait AuthCalls {
+    fn authenticate(&mut self) -> Result<(), PamError>;
+    fn end(&mut self) -> Result<(), PamError>;
+    fn last_code(&self) -> PamReturnCode;
+}
+
+impl AuthCalls for PamSession<'_> {
+    fn authenticate(&mut self) -> Result<(), PamError> {
+        PamSession::authenticate(self, PamFlag::NONE)
+    }
+    fn end(&mut self) -> Result<(), PamError> {
+        PamSession::end(self)
+    }
+    fn last_code(&self) -> PamReturnCode {
+        PamSession::last_code(self)
+    }
+}
+
+/// Authenticate the user, first trying the cached service if there is one.
+/// If the cached service authenticates the user, the login continues in its
+/// transaction, so that the account and session stacks see what its auth
+/// stack left behind, such as tokens or credential caches. The transaction
+/// of the login is then ended, as its auth stack never ran.
+fn authenticate_user<P: AuthCalls>(
+    pam: &mut P,
+    cached: Option<P>,
+    timings: &mut Timings,
+) -> Result<AuthPath, Error> {
+    let start = Instant::now();
+    let path = authenticate_path(pam, cached)?;
+    timings.record("authenticate", start);
+    Ok(path)
+}
+
+fn authenticate_path<P: AuthCalls>(pam: &mut P, cached: Option<P>) -> Result<AuthPath, Error> {
+    if let Some(mut cached) = cached {
+        match cached.authenticate() {
+            Ok(()) => {
+                let mut login = std::mem::replace(pam, cached);
+                if let Err(e) = login.end() {
+                    eprintln!("session: unable to end PAM transaction: {}", e);
+                }
+                return Ok(AuthPath::Cached);
+            }
+            Err(e) => {
+                let miss = is_cache_miss(cached.last_code());
+                if let Err(e) = cached.end() {
+                    eprintln!("session: unable to end PAM transaction: {}", e);
+                }
+                if !miss {
+                    return Err(e.into());
+                }
+                eprintln!("session: cached authentication failed, prompting: {}", e)
+            }
+        }
+    }
+    pam.authenticate()?;
+    Ok(AuthPath::Interactive)
+}
+
+/// The PAM calls that establish and tear down the session, whose order
+/// depends on the options of the login.
+trait SessionCalls {
+    fn acct_mgmt(&mut self) -> Result<(), PamError>;
+    fn setcred(&mut self, flags: PamFlag) -> Result<(), PamError>;
+    fn open_session(&mut self) -> Result<(), PamError>;
+    fn close_session(&mut self) -> Result<(), PamError>;
+    fn last_code(&self) -> PamReturnCode;
+}
+
+impl SessionCalls for PamSession<'_> {
+    fn acct_mgmt(&mut self) -> Result<(), PamError> {
+        PamSession::acct_mgmt(self, PamFlag::NONE)
+    }
+    fn setcred(&mut self, flags: PamFlag) -> Result<(), PamError> {
+        PamSession::setcred(self, flags)
+    }
+    fn open_session(&mut self) -> Result<(), PamError> {
+        PamSession::open_session(self, PamFlag::NONE)
+    }
+    fn close_session(&mut self) -> Result<(), PamError> {
+        PamSession::close_session(self, PamFlag::NONE)
+    }
+    fn last_code(&self) -> PamReturnCode {
+        PamSession::last_code(self)
+    }
+}
+
+/// Check that the account may log in, and establish its credentials unless
+/// that is to be done after open_session.
+fn authorize_account<P: SessionCalls>(
+    pam: &mut P,
+    options: &LoginOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    pam.acct_mgmt()?;
+    timings.record("acct_mgmt", start);
+
+    // Not the credentials you think.
+    if !options.setcred_after_open {
+        let start = Instant::now();
+        pam.setcred(PamFlag::ESTABLISH_CRED)?;
+        timings.record("setcred", start);
+    }
+    Ok(())
+}
+
+/// Open the session, and establish credentials if that was deferred. Opening
+/// the session may fail transiently if the services our PAM modules depend
+/// on are not yet ready, so those failures are retried as requested.
+fn open_session<P: SessionCalls>(
+    pam: &mut P,
+    options: &LoginOptions,
+    timings: &mut Timings,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        match pam.open_session() {
+            Ok(()) => break,
+            Err(e)
+                if attempt < options.open_session_retries
+                    && is_transient_session_error(pam.last_code()) =>
+            {
+                attempt += 1;
+                eprintln!(
+                    "session: {}, retrying ({}/{})",
+                    e, attempt, options.open_session_retries
+                );
+                thread::sleep(open_session_backoff(
+                    options.open_session_backoff_ms,
+                    attempt,
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    timings.record("open_session", start);
+
+    if options.setcred_after_open {
+        let start = Instant::now();
+        pam.setcred(PamFlag::ESTABLISH_CRED)?;
+        timings.record("setcred", start);
+    }
+    Ok(())
+}
+
+/// Close the session, and then delete the credentials. This is the reverse
+/// of the default order of setup, and is kept regardless of it, as modules
+/// may need the credentials to close the session.
+fn close_session<P: SessionCalls>(pam: &mut P) -> Result<(), Error> {
+    pam.close_session()?;
+    pam.setcred(PamFlag::DELETE_CRED)?;
+    Ok(())
+}
+
+/// What the inner child needs to turn itself into the session.
+struct SessionSetup<'a> {
+    options: &'a LoginOptions,
+    user: &'a CStr,
+    user_gid: Gid,
+    uid: Uid,
+    gid: Gid,
+    home: &'a OsStr,
+    cgroup: Option<&'a Cgroup>,
+    cpus: Option<&'a CpuSet>,
+    log_file: Option<&'a File>,
+    cmd: &'a [String],
+    command: &'a str,
+}
+
+/// Turn the inner child into the session: enter its cgroup, drop to the
+/// user, apply the settings of the session and execute its command. This
+/// only returns if a step failed, with the reason, upon which the child must
+/// exit rather than return to the worker.
+fn exec_inner_child<O: PrivilegeDropper + Executor>(
+    ops: &O,
+    setup: &SessionSetup,
+    pamenv: &mut PamEnv,
+    status: RawFd,
+) -> String {
+    let options = setup.options;
+
+    // Enter the session cgroup while we still have the privileges to do so.
+    if let Some(cgroup) = setup.cgroup {
+        if let Err(e) = cgroup.add_process(getpid()) {
+            return format!("unable to enter session cgroup: {}", e);
+        }
+    }
+
+    // Drop privileges to target user, and set our parent death signal.
+    if let Err(e) = drop_privileges(
+        ops,
+        setup.user,
+        setup.user_gid,
+        setup.uid,
+        setup.gid,
+        libc::SIGTERM,
+    ) {
+        return e;
+    }
+
+    // Give the session a keyring of its own. This is done as the user, so
+    // that the user owns the keyring.
+    if options.new_session_keyring {
+        match join_session_keyring() {
+            Ok(Some(_)) => (),
+            Ok(None) => eprintln!("session: kernel keyrings are not supported"),
+            Err(e) => eprintln!("session: unable to create session keyring: {}", e),
+        }
+    }
+
+    // Pin the session to the requested CPUs. Failure is reported to the
+    // worker, but does not prevent the session from starting.
+    if let Some(cpus) = setup.cpus {
+        if sched_setaffinity(Pid::from_raw(0), cpus).is_err() {
+            let _ = write(status, &[EXEC_STATUS_AFFINITY]);
+        }
+    }
+
+    // Enter the home directory with the credentials of the user, and correct
+    // PWD if we had to fall back.
+    if options.chdir_as_user {
+        let pwd = match enter_home(setup.home) {
+            Ok(pwd) => pwd,
+            Err(e) => return format!("unable to set working directory: {}", e),
+        };
+        if pamenv.get("PWD") != Some(pwd) {
+            if let Err(e) = pamenv.set("PWD", pwd) {
+                return format!("unable to set PWD: {}", e);
+            }
+        }
+    }
+
+    if !options.inherit_sigpipe {
+        if let Err(e) = reset_sigpipe() {
+            return format!("unable to reset SIGPIPE: {}", e);
+        }
+    }
+
+    if let Some(log_file) = setup.log_file {
+        if let Err(e) = connect_log(log_file) {
+            return format!("unable to connect log file: {}", e);
+        }
+    }
+
+    let command = match CString::new(setup.command) {
+        Ok(command) => command,
+        Err(e) => return format!("invalid session command: {}", e),
+    };
+
+    // Run. The program of the session is looked for as the user, and only if
+    // there is a fallback to start instead.
+    let found = options.fallback_cmd.is_none() || program_found(setup.cmd, pamenv.get("PATH"));
+    let err = exec_session(
+        ops,
+        &[
+            CString::new("/bin/sh").unwrap(),
+            CString::new("-c").unwrap(),
+            command,
+        ],
+        found,
+        options.fallback_cmd.as_deref(),
+        &pamenv.to_vec(),
+        status,
+    );
+    format!("unable to exec: {}", err)
+}
+
+/// A login in progress, from the start of the PAM transaction until the
+/// session is run.
+pub struct Login<'a> {
+    pam: PamSession<'a>,
+    conv: Rc<Pin<Box<dyn Converse + 'a>>>,
+    user: &'a str,
+    class: String,
+    source_profile: bool,
+    options: LoginOptions,
+    cmd: Option<Vec<String>>,
+    handoff: bool,
+    timings: Timings,
+}
+
+impl<'a> Login<'a> {
+    /// Start a PAM transaction for the user with the specified service and
+    /// session class, such as "user" or "greeter".
+    pub fn start(
+        service: &str,
+        class: &str,
+        user: &'a str,
+        conv: Pin<Box<dyn Converse + 'a>>,
+        source_profile: bool,
+        options: LoginOptions,
+    ) -> Result<Login<'a>, Error> {
+        validate_service(service)?;
+        if let Some(cached) = &options.auth_service_cached {
+            validate_service(cached)?;
+        }
+        if let Some(mode) = options.tty_owner_mode {
+            if mode & !0o777 != 0 {
+                return Err(Error::ProtocolError(format!(
+                    "invalid terminal mode: {:o}",
+                    mode
+                )));
+            }
+        }
+        if let Some(cmd) = &options.fallback_cmd {
+            if cmd.is_empty() {
+                return Err(Error::ProtocolError("empty fallback command".to_string()));
+            }
+        }
+
+        let conv = Rc::new(conv);
+        let shared = SharedConv {
+            conv: conv.clone(),
+            prompting: Rc::new(Cell::new(true)),
+        };
+        let pam = PamSession::start(service, user, Box::pin(shared), options.conv_encoding)?;
+        Ok(Login {
+            pam,
+            conv,
+            user,
+            class: class.to_string(),
+            source_profile,
+            options,
+            cmd: None,
+            handoff: false,
+            timings: Timings::default(),
+        })
+    }
+
+    /// Authenticate the user through the PAM conversation, first trying the
+    /// cached service if there is one. If the cached service authenticates
+    /// the user, the login continues with that service, and its transaction
+    /// talks to the greeter from then on.
+    pub fn authenticate(&mut self) -> Result<AuthPath, Error> {
+        let prompting = Rc::new(Cell::new(false));
+        let cached = match &self.options.auth_service_cached {
+            Some(service) => Some(PamSession::start(
+                service,
+                self.user,
+                Box::pin(SharedConv {
+                    conv: self.conv.clone(),
+                    prompting: prompting.clone(),
+                }),
+                self.options.conv_encoding,
+            )?),
+            None => None,
+        };
+        let path = authenticate_user(&mut self.pam, cached, &mut self.timings)?;
+        prompting.set(true);
+        Ok(path)
+    }
+
+    /// Whether authentication tries a cached service first.
+    pub fn has_cached_service(&self) -> bool {
+        self.options.auth_service_cached.is_some()
+    }
+
+    /// Check that the account may log in, and establish its credentials.
+    /// This is required even if the user was not authenticated.
+    pub fn authorize(&mut self) -> Result<(), Error> {
+        authorize_account(&mut self.pam, &self.options, &mut self.timings)
+    }
+
+    /// Set the command to run as the session.
+    pub fn set_args(&mut self, cmd: Vec<String>) {
+        self.cmd = Some(cmd);
+    }
+
+    /// Take over the terminal from a greeter that has exited, rather than
+    /// clearing it.
+    pub fn hand_off(&mut self) {
+        self.handoff = true;
+    }
+
+    /// Abandon the login, ending the PAM transaction.
+    pub fn cancel(mut self) {
+        if let Err(e) = self.pam.end() {
+            eprintln!("session: unable to end PAM transaction: {}", e);
+        }
+    }
+
+    /// How long each phase of the login has taken so far.
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    /// Open the session on the specified terminal, and start the session
+    /// command as the user.
+    pub fn run(self, tty: TerminalMode) -> Result<SessionHandle<'a>, Error> {
+        self.run_timed(tty).map_err(|(e, _)| e)
+    }
+
+    /// Run the session as with run, but hand back how long each phase that
+    /// completed took along with any error.
+    pub fn run_timed(mut self, tty: TerminalMode) -> Result<SessionHandle<'a>, (Error, Timings)> {
+        let mut timings = std::mem::take(&mut self.timings);
+        match self.start_session(tty, &mut timings) {
+            Ok(mut session) => {
+                session.timings = timings;
+                Ok(session)
+            }
+            Err(e) => Err((e, timings)),
+        }
+    }
+
+    fn start_session(
+        mut self,
+        tty: TerminalMode,
+        timings: &mut Timings,
+    ) -> Result<SessionHandle<'a>, Error> {
+        let cmd = self.cmd.take().ok_or("no session command set")?;
+        tty.validate()?;
+        let options = &self.options;
+        let pam = &mut self.pam;
+
+        let pam_username = pam.get_user()?;
+
+        // Make this process a session leader.
+        setsid().map_err(|e| format!("unable to become session leader: {}", e))?;
+
+        // The utmp line of the session, if it is to be recorded.
+        let utmp_line = match &tty {
+            TerminalMode::Terminal { path, .. } if options.write_utmp => {
+                Some(path.trim_start_matches("/dev/").to_string())
+            }
+            _ => None,
+        };
+
+        let mut grace = None;
+        let mut tty_path = None;
+        match tty {
+            TerminalMode::Stdin => (),
+            TerminalMode::Terminal { path, vt, switch } => {
+                // Tell PAM what TTY we're targetting, which is used by logind.
+                pam.set_item(PamItemType::TTY, &format!("tty{}", vt))?;
+                pam.putenv(format!("XDG_VTNR={}", vt))?;
+
+                // Opening our target terminal, and preparing it for the session.
+                let start = Instant::now();
+                let target_term = terminal::Terminal::open(&path)?;
+                setup_session_terminal(&target_term, vt, switch, self.handoff)?;
+                if !options.keep_termios {
+                    target_term.term_sane()?;
+                }
+                timings.record("terminal", start);
+                if options.vt_switch_grace_ms > 0 {
+                    let duration = Duration::from_millis(options.vt_switch_grace_ms);
+                    grace = Some(SwitchGrace::hold(target_term, duration)?);
+                }
+                tty_path = Some(path);
+            }
+        }
+
+        // Name this worker after the session. The inner child gets the name of
+        // whatever it executes.
+        if let Some(template) = &options.process_name {
+            let name = process_name(template, &pam_username);
+            if let Err(e) = prctl(PrctlOption::SET_NAME(&name)) {
+                eprintln!("session: unable to set process name: {}", e);
+            }
+        }
+
+        // PAM has to be provided a bunch of environment variables before
+        // open_session. We pass any environment variables from our greeter
+        // through here as well. This allows them to affect PAM (more
+        // specifically, pam_systemd.so), as well as make it easier to gather
+        // and set all environment variables later.
+        let greetd_sock = env::var("GREETD_SOCK").ok();
+        let prepared_env = prepared_env(&self.class, greetd_sock.as_deref(), options);
+
+        for e in prepared_env.iter() {
+            pam.putenv(e)?;
+        }
+
+        // The variables describing the user are among them, with the home
+        // directory optimistically assumed for PWD, as it may only be created
+        // by open_session.
+        let early_user = users::get_user_by_name(&pam_username);
+        if let Some(user) = &early_user {
+            let home = user.home_dir().as_os_str();
+            for e in user_env(user.name(), home, user.shell().as_os_str(), home).iter() {
+                pam.putenv(e)?;
+            }
+        }
+
+        // Session time!
+        open_session(pam, options, timings)?;
+
+        // PAM modules that provision users may only have created the user
+        // during open_session, in which case it is looked up again now.
+        let user = match early_user {
+            Some(user) => user,
+            None => lookup_user(
+                &pam_username,
+                options.user_lookup_retries,
+                options.user_lookup_delay_ms,
+                users::get_user_by_name,
+            )?,
+        };
+        let username = user.name();
+        let home = user.home_dir().as_os_str();
+        let shell = user.shell().as_os_str();
+        let uid = Uid::from_raw(user.uid());
+        let user_gid = Gid::from_raw(user.primary_group_id());
+        let gid = session_group(
+            &user,
+            options.primary_group.as_deref(),
+            users::get_group_by_name,
+        )?;
+
+        // Change working directory, unless this is to be done with the
+        // credentials of the user, in which case we optimistically assume the
+        // home directory for PWD.
+        let pwd = if options.chdir_as_user {
+            home
+        } else {
+            enter_home(home)?
+        };
+
+        // Set the variables again, for a user that was only found now, and
+        // for a PWD that fell back.
+        for e in user_env(username, home, shell, pwd).iter() {
+            pam.putenv(e)?;
+        }
+
+        // Load the user's own environment files. This is done after
+        // open_session, as PAM may be what makes the home directory available.
+        // The files override the environment we prepared, as well as anything
+        // set by PAM modules.
+        if let Some(dir) = &options.user_env_dir {
+            let dir = Path::new(home).join(dir);
+            let cusername = CString::new(username.as_bytes())?;
+            match load_as_user(&dir, &cusername, user_gid, uid, gid) {
+                Ok(vars) => {
+                    for (key, value) in vars {
+                        pam.putenv(format!("{}={}", key, value))?;
+                    }
+                }
+                Err(e) => eprintln!(
+                    "session: unable to load environment from {}: {}",
+                    dir.display(),
+                    e
+                ),
+            }
+        }
+
+        // Normally pam_loginuid takes care of this, but not all PAM stacks
+        // include it. The loginuid is inherited by the session child.
+        if options.set_loginuid {
+            if let Err(e) = set_loginuid(Path::new(LOGINUID_PATH), uid) {
+                eprintln!("session: unable to set loginuid: {}", e);
+            }
+        }
+
+        // Prepare some strings in C format that we'll need.
+        let cusername = CString::new(username.as_bytes())?;
+        let command = session_command(&cmd, self.source_profile, options.profile_strict);
+
+        // Extract PAM environment for use with execve below.
+        let mut pamenv = pam.getenvlist()?;
+
+        scrub_env(&mut pamenv, options);
+        forward_env(&mut pamenv, &options.forward_env)?;
+
+        // The session cookie is added only now, so that it is seen by neither
+        // PAM modules nor anything but the session itself. Any cookie that found
+        // its way into the PAM environment is replaced.
+        if let Some(cookie) = &options.session_cookie {
+            cookie.apply(&mut pamenv)?;
+        }
+
+        // The log file is opened here, as the user may not be able to open
+        // it, and handed over to the user.
+        let log_file = match &options.log_file {
+            Some(path) => Some(
+                open_log(Path::new(path), options.log_append, uid, gid)
+                    .map_err(|e| format!("unable to open log file {}: {}", path, e))?,
+            ),
+            None => None,
+        };
+
+        let cpus = match &options.cpu_affinity {
+            Some(cpus) => {
+                let allowed = sched_getaffinity(Pid::from_raw(0))
+                    .map_err(|e| format!("unable to get CPU affinity: {}", e))?;
+                Some(cpu_set(cpus, &allowed)?)
+            }
+            None => None,
+        };
+
+        // The cgroup is removed when dropped, should the session fail to
+        // start from here on.
+        let cgroup = match &options.cgroup {
+            Some(name) => Some(Cgroup::create(
+                Path::new(CGROUP_ROOT),
+                name,
+                &options.cgroup_limits,
+            )?),
+            None => None,
+        };
+
+        // Hand the terminal to the user before the privileges are dropped.
+        let tty_owner = match (options.tty_owner_mode, &tty_path) {
+            (Some(mode), Some(path)) => Some(
+                TtyOwner::take(Path::new(path), uid, mode)
+                    .map_err(|e| format!("unable to hand terminal to user: {}", e))?,
+            ),
+            _ => None,
+        };
+
+        // A pipe through which the inner child reports whether it had to resort
+        // to the fallback command. It is closed by a successful exec.
+        let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC)?;
+
+        // PAM is weird and gets upset if you exec from the process that opened
+        // the session, registering it automatically as a log-out. Thus, we must
+        // exec in a new child.
+        let fork_start = Instant::now();
+        let (child, started_at) = match fork().map_err(|e| format!("unable to fork: {}", e))? {
+            ForkResult::Parent { child, .. } => (child, SystemTime::now()),
+            ForkResult::Child => {
+                // It is important that we do *not* return from here by
+                // accidentally using '?'. The process *must* exit from within
+                // this match arm.
+                let _ = close(status_read);
+                let setup = SessionSetup {
+                    options,
+                    user: &cusername,
+                    user_gid,
+                    uid,
+                    gid,
+                    home,
+                    cgroup: cgroup.as_ref(),
+                    cpus: cpus.as_ref(),
+                    log_file: log_file.as_ref(),
+                    cmd: &cmd,
+                    command: &command,
+                };
+                let err = exec_inner_child(&System, &setup, &mut pamenv, status_write);
+                panic!("{}", err);
+            }
+        };
+
+        close(status_write)?;
+        let status = read_exec_status(status_read)?;
+        // The session has been forked and set up once it has been executed.
+        timings.record("fork", fork_start);
+        if status.contains(&EXEC_STATUS_AFFINITY) {
+            eprintln!("session: unable to set CPU affinity of session");
+        }
+        let mut fallback = None;
+        if status.contains(&EXEC_STATUS_FALLBACK) {
+            if status.contains(&EXEC_STATUS_FAILED) {
+                eprintln!("session: unable to execute session or fallback command");
+            } else {
+                eprintln!("session: unable to execute session, started fallback command");
+                fallback = options.fallback_cmd.clone();
+            }
+        }
+
+        if let Some(line) = &utmp_line {
+            if let Err(e) = utmp::login(
+                Path::new(UTMP_PATH),
+                Path::new(WTMP_PATH),
+                line,
+                username,
+                child,
+            ) {
+                eprintln!("session: unable to write utmp entry: {}", e);
+            }
+        }
+
+        Ok(SessionHandle {
+            pam: self.pam,
+            child,
+            started_at,
+            utmp_line,
+            cgroup,
+            fallback,
+            grace,
+            tty_owner,
+            timings: Timings::default(),
+        })
+    }
+}
+
+/// Verify the credentials of a user without opening a session, such as to
+/// unlock a locked session. Only authentication is run, so the service may
+/// be one dedicated to unlocking.
+pub fn reauthenticate<'a>(
+    service: &str,
+    user: &'a str,
+    conv: Pin<Box<dyn Converse + 'a>>,
+) -> Result<(), Error> {
+    let mut login = Login::start(service, "user", user, conv, false, Default::default())?;
+    let res = login.authenticate();
+    login.cancel();
+    res.map(drop)
+}
+
+/// Change the authentication token of a user without opening a session,
+/// prompting for the current and new tokens as the service directs.
+pub fn change_authtok<'a>(
+    service: &str,
+    user: &'a str,
+    conv: Pin<Box<dyn Converse + 'a>>,
+) -> Result<(), Error> {
+    let mut login = Login::start(service, "user", user, conv, false, Default::default())?;
+    let res = login.pam.chauthtok(PamFlag::NONE);
+    login.cancel();
+    res?;
+    Ok(())
+}
+
+/// A running session, which must be waited for to close it.
+pub struct SessionHandle<'a> {
+    pam: PamSession<'a>,
+    child: Pid,
+    started_at: SystemTime,
+    utmp_line: Option<String>,
+    cgroup: Option<Cgroup>,
+    fallback: Option<Vec<String>>,
+    grace: Option<SwitchGrace<Terminal>>,
+    tty_owner: Option<TtyOwner>,
+    timings: Timings,
+}
+
+impl<'a> SessionHandle<'a> {
+    /// The pid of the session process.
+    pub fn pid(&self) -> Pid {
+        self.child
+    }
+
+    /// When the session process was forked.
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// How long each phase of the login took.
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    /// The fallback command, if it was started because the session command
+    /// could not be executed.
+    pub fn fallback(&self) -> Option<&[String]> {
+        self.fallback.as_deref()
+    }
+
+    /// Wait for the session process to terminate, and close the session.
+    pub fn wait(mut self) -> Result<(), Error> {
+        let child = self.child;
+
+        // Let the session have its VT once the grace window is over.
+        if let Some(grace) = self.grace.take() {
+            grace.finish();
+        }
+
+        // Wait for process to terminate, handling EINTR as necessary.
+        loop {
+            match waitpid(child, None) {
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => {
+                    eprintln!("session: waitpid on inner child failed: {}", e);
+                    break;
+                }
+                Ok(_) => break,
+            }
+        }
+
+        if let Some(line) = &self.utmp_line {
+            if let Err(e) = utmp::logout(Path::new(UTMP_PATH), Path::new(WTMP_PATH), line, child) {
+                eprintln!("session: unable to write utmp entry: {}", e);
+            }
+        }
+
+        if let Some(owner) = self.tty_owner.take() {
+            if let Err(e) = owner.restore() {
+                eprintln!("session: unable to restore terminal ownership: {}", e);
+            }
+        }
+
+        if let Some(cgroup) = self.cgroup {
+            if let Err(e) = cgroup.remove() {
+                eprintln!("session: unable to remove session cgroup: {}", e);
+            }
+        }
+
+        // Close the session. This step requires root privileges to run, as it
+        // will result in various forms of login teardown (including unmounting
+        // home folders, telling logind that the session ended, etc.). This is
+        // why we cannot drop privileges in this process, but must do it in the
+        // inner-most child.
+        close_session(&mut self.pam)?;
+        self.pam.end()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{
+        sys::wait::WaitStatus,
+        unistd::{execve, getgid, getuid},
+    };
+    use std::{cell::RefCell, os::unix::fs::PermissionsExt};
+
+    struct NullConv;
+
+    impl Converse for NullConv {
+        fn prompt_echo(&self, _msg: &str) -> Result<String, ()> {
+            Err(())
+        }
+        fn prompt_blind(&self, _msg: &str) -> Result<String, ()> {
+            Err(())
+        }
+        fn info(&self, _msg: &str) -> Result<(), ()> {
+            Ok(())
+        }
+        fn error(&self, _msg: &str) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn start(service: &str, options: LoginOptions) -> Result<Login<'static>, Error> {
+        Login::start(
+            service,
+            "user",
+            "nobody",
+            Box::pin(NullConv),
+            false,
+            options,
+        )
+    }
+
+    #[test]
+    fn login_usage() {
+        assert!(matches!(
+            start("../login", Default::default()),
+            Err(Error::ProtocolError(_))
+        ));
+        assert!(matches!(
+            start(
+                "greetd-test",
+                LoginOptions {
+                    fallback_cmd: Some(vec![]),
+                    ..Default::default()
+                }
+            ),
+            Err(Error::ProtocolError(_))
+        ));
+        assert!(matches!(
+            start(
+                "greetd-test",
+                LoginOptions {
+                    tty_owner_mode: Some(0o4620),
+                    ..Default::default()
+                }
+            ),
+            Err(Error::ProtocolError(_))
+        ));
+
+        // Starting the PAM transaction needs no configuration for the
+        // service, but running a session needs a command.
+        let login = start("greetd-test", Default::default()).unwrap();
+        login.cancel();
+
+        let login = start("greetd-test", Default::default()).unwrap();
+        assert!(login.run(TerminalMode::Stdin).is_err());
+
+        // Without answers to its prompts, no token can be changed.
+        assert!(change_authtok("greetd-test", "nobody", Box::pin(NullConv)).is_err());
+    }
+
+    fn test_env(options: &LoginOptions) -> Vec<String> {
+        prepared_env("user", Some("/run/greetd-test.sock"), options)
+    }
+
+    #[test]
+    fn greetd_sock() {
+        let env = test_env(&Default::default());
+        assert!(env.contains(&"GREETD_SOCK=/run/greetd-test.sock".to_string()));
+
+        // Without a greetd daemon, there is no socket to pass on.
+        let env = prepared_env("user", None, &Default::default());
+        assert!(!env.iter().any(|e| e.starts_with("GREETD_SOCK=")));
+    }
+
+    #[test]
+    fn session_desktop() {
+        let env = test_env(&Default::default());
+        assert!(!env.iter().any(|e| e.starts_with("XDG_SESSION_DESKTOP=")));
+
+        let options = LoginOptions {
+            session_desktop: Some("sway".to_string()),
+            ..Default::default()
+        };
+        let env = test_env(&options);
+        assert!(env.contains(&"XDG_SESSION_DESKTOP=sway".to_string()));
+
+        // It is put in the PAM environment, where logind picks it up during
+        // open_session, and which the session environment is taken from.
+        let mut pam = PamSession::start(
+            "greetd-test",
+            "nobody",
+            Box::pin(NullConv),
+            options.conv_encoding,
+        )
+        .unwrap();
+        for e in env.iter() {
+            pam.putenv(e).unwrap();
+        }
+        let mut pamenv = pam.getenvlist().unwrap();
+        assert_eq!(pamenv.get("XDG_SESSION_DESKTOP"), Some(OsStr::new("sway")));
+        scrub_env(&mut pamenv, &options);
+
+        let (out_read, out_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let args: Vec<CString> = ["/bin/sh", "-c", "printf %s \"$XDG_SESSION_DESKTOP\""]
+            .iter()
+            .map(|a| CString::new(*a).unwrap())
+            .collect();
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                close(out_write).unwrap();
+                close(status_write).unwrap();
+                assert!(read_exec_status(status_read).unwrap().is_empty());
+                let out = read_exec_status(out_read).unwrap();
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+                assert_eq!(out, b"sway");
+            }
+            ForkResult::Child => {
+                dup2(out_write, 1).unwrap();
+                exec_session(&System, &args, true, None, &pamenv.to_vec(), status_write);
+                unsafe { libc::_exit(99) };
+            }
+        }
+    }
+
+    #[test]
+    fn xkb_env() {
+        let env = test_env(&Default::default());
+        assert!(!env.iter().any(|e| e.starts_with("XKB_")));
+
+        let env = test_env(&LoginOptions {
+            xkb_layout: Some("de,us".to_string()),
+            xkb_options: Some("grp:alt_shift_toggle".to_string()),
+            ..Default::default()
+        });
+        assert!(env.contains(&"XKB_DEFAULT_LAYOUT=de,us".to_string()));
+        assert!(env.contains(&"XKB_DEFAULT_OPTIONS=grp:alt_shift_toggle".to_string()));
+        assert!(!env.iter().any(|e| e.starts_with("XKB_DEFAULT_VARIANT=")));
+    }
+
+    fn scrubbed(options: &LoginOptions) -> Vec<String> {
+        let mut env = PamEnv::default();
+        for (key, value) in &[
+            ("HOME", "/home/john"),
+            ("GREETD_SOCK", "/run/greetd.sock"),
+            ("GREETD_GREETER_STATE", "step2"),
+            ("GREETD_SESSION_ID", "42"),
+            ("XGREETD_OTHER", "x"),
+        ] {
+            env.set(key, value).unwrap();
+        }
+        scrub_env(&mut env, options);
+        env.iter()
+            .map(|(k, _)| k.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn env_scrub() {
+        assert_eq!(
+            scrubbed(&Default::default()),
+            vec!["HOME", "GREETD_SOCK", "XGREETD_OTHER"]
+        );
+        assert_eq!(
+            scrubbed(&LoginOptions {
+                env_scrub_keep: vec!["GREETD_SESSION_ID".to_string()],
+                ..Default::default()
+            }),
+            vec!["HOME", "GREETD_SOCK", "GREETD_SESSION_ID", "XGREETD_OTHER"]
+        );
+        assert_eq!(
+            scrubbed(&LoginOptions {
+                env_scrub_prefix: Some("XGREETD_".to_string()),
+                ..Default::default()
+            }),
+            vec![
+                "HOME",
+                "GREETD_SOCK",
+                "GREETD_GREETER_STATE",
+                "GREETD_SESSION_ID"
+            ]
+        );
+        assert_eq!(
+            scrubbed(&LoginOptions {
+                env_scrub_prefix: Some("".to_string()),
+                ..Default::default()
+            })
+            .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn env_forwarding() {
+        env::set_var("GREETD_TEST_PROXY", "http://proxy:3128");
+        env::set_var("GREETD_TEST_DEBUG", "1");
+        env::set_var("GREETD_TEST_UNLISTED", "secret");
+        env::remove_var("GREETD_TEST_MISSING");
+
+        let mut env = PamEnv::default();
+        env.set("GREETD_TEST_DEBUG", "0").unwrap();
+        forward_env(
+            &mut env,
+            &[
+                "GREETD_TEST_PROXY".to_string(),
+                "GREETD_TEST_DEBUG".to_string(),
+                "GREETD_TEST_MISSING".to_string(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            env.get("GREETD_TEST_PROXY"),
+            Some(OsStr::new("http://proxy:3128"))
+        );
+        assert_eq!(env.get("GREETD_TEST_DEBUG"), Some(OsStr::new("0")));
+        assert_eq!(env.get("GREETD_TEST_MISSING"), None);
+        assert_eq!(env.get("GREETD_TEST_UNLISTED"), None);
+    }
+
+    #[test]
+    fn open_session_retry() {
+        assert!(is_transient_session_error(PamReturnCode::SYSTEM_ERR));
+        assert!(is_transient_session_error(PamReturnCode::SESSION_ERR));
+        assert!(!is_transient_session_error(PamReturnCode::PERM_DENIED));
+        assert!(!is_transient_session_error(PamReturnCode::ABORT));
+
+        assert_eq!(open_session_backoff(100, 1), Duration::from_millis(100));
+        assert_eq!(open_session_backoff(100, 2), Duration::from_millis(200));
+        assert_eq!(open_session_backoff(100, 4), Duration::from_millis(800));
+        assert_eq!(open_session_backoff(0, 3), Duration::from_millis(0));
+        assert_eq!(
+            open_session_backoff(u64::MAX, 30),
+            Duration::from_millis(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn user_lookup_retry() {
+        // A user that is only provisioned some time after open_session.
+        let mut lookups = 0;
+        let provisioned = |name: &str| {
+            lookups += 1;
+            if lookups < 3 {
+                None
+            } else {
+                Some(User::new(1000, name, 1000))
+            }
+        };
+        let user = lookup_user("john", 2, 0, provisioned).unwrap();
+        assert_eq!(user.name(), "john");
+        assert_eq!(user.uid(), 1000);
+
+        let mut lookups = 0;
+        let provisioned = |name: &str| {
+            lookups += 1;
+            if lookups < 3 {
+                None
+            } else {
+                Some(User::new(1000, name, 1000))
+            }
+        };
+        let err = lookup_user("john", 1, 0, provisioned).unwrap_err();
+        assert_eq!(err.to_string(), "user john does not exist");
+    }
+
+    #[test]
+    fn cpu_affinity() {
+        // CPU 1 is offline.
+        let mut allowed = CpuSet::new();
+        for cpu in &[0, 2, 3] {
+            allowed.set(*cpu).unwrap();
+        }
+
+        let set = cpu_set(&[0, 2], &allowed).unwrap();
+        assert!(set.is_set(0).unwrap());
+        assert!(!set.is_set(1).unwrap());
+        assert!(set.is_set(2).unwrap());
+
+        assert!(matches!(
+            cpu_set(&[], &allowed),
+            Err(Error::ProtocolError(_))
+        ));
+        let err = cpu_set(&[1, 3, 4], &allowed).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "protocol error: invalid CPUs in affinity: 1, 4 (not available to greetd)"
+        );
+        let err = cpu_set(&[CpuSet::count()], &allowed).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "protocol error: invalid CPUs in affinity: {} (not available to greetd)",
+                CpuSet::count()
+            )
+        );
+    }
+
+    #[test]
+    fn cached_auth() {
+        assert!(is_cache_miss(PamReturnCode::AUTH_ERR));
+        assert!(is_cache_miss(PamReturnCode::CONV_ERR));
+        assert!(is_cache_miss(PamReturnCode::CRED_INSUFFICIENT));
+        assert!(is_cache_miss(PamReturnCode::AUTHINFO_UNAVAIL));
+        assert!(!is_cache_miss(PamReturnCode::USER_UNKNOWN));
+        assert!(!is_cache_miss(PamReturnCode::MAXTRIES));
+        assert!(!is_cache_miss(PamReturnCode::ABORT));
+
+        // The transaction of the cached service only prompts once the login
+        // continues in it.
+        let prompting = Rc::new(Cell::new(false));
+        let conv = SharedConv {
+            conv: Rc::new(Box::pin(NullConv)),
+            prompting: prompting.clone(),
+        };
+        assert!(conv.prompt_blind("Password:").is_err());
+        assert!(conv.prompt_echo("Username:").is_err());
+        assert!(conv.info("Cached").is_ok());
+        prompting.set(true);
+        assert!(conv.info("Cached").is_ok());
+
+        assert!(matches!(
+            start(
+                "greetd-test",
+                LoginOptions {
+                    auth_service_cached: Some("../cache".to_string()),
+                    ..Default::default()
+                }
+            ),
+            Err(Error::ProtocolError(_))
+        ));
+        let login = start(
+            "greetd-test",
+            LoginOptions {
+                auth_service_cached: Some("greetd-cache".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(login.has_cached_service());
+        login.cancel();
+    }
+
+    /// Records the authentication calls of a transaction in a log shared
+    /// with the other transaction, failing them with the specified code.
+    struct MockAuth {
+        name: &'static str,
+        rc: PamReturnCode,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl MockAuth {
+        fn new(name: &'static str, rc: PamReturnCode, log: &Rc<RefCell<Vec<String>>>) -> MockAuth {
+            MockAuth {
+                name,
+                rc,
+                log: log.clone(),
+            }
+        }
+
+        fn call(&self, call: &str) {
+            self.log
+                .borrow_mut()
+                .push(format!("{} {}", self.name, call));
+        }
+    }
+
+    impl AuthCalls for MockAuth {
+        fn authenticate(&mut self) -> Result<(), PamError> {
+            self.call("authenticate");
+            match self.rc {
+                PamReturnCode::SUCCESS => Ok(()),
+                rc => Err(PamError::from_rc("pam_authenticate", rc)),
+            }
+        }
+        fn end(&mut self) -> Result<(), PamError> {
+            self.call("end");
+            Ok(())
+        }
+        fn last_code(&self) -> PamReturnCode {
+            self.rc
+        }
+    }
+
+    #[test]
+    fn cached_auth_paths() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let run = |cached: Option<PamReturnCode>| {
+            log.borrow_mut().clear();
+            let mut pam = MockAuth::new("login", PamReturnCode::SUCCESS, &log);
+            let cached = cached.map(|rc| MockAuth::new("cached", rc, &log));
+            let res = authenticate_path(&mut pam, cached);
+            (res, pam.name, log.borrow().clone())
+        };
+
+        // A hit continues the login in the cached transaction, and ends the
+        // transaction of the login, whose auth stack never ran.
+        let (res, pam, calls) = run(Some(PamReturnCode::SUCCESS));
+        assert_eq!(res.unwrap(), AuthPath::Cached);
+        assert_eq!(pam, "cached");
+        assert_eq!(calls, vec!["cached authenticate", "login end"]);
+
+        // A miss ends the cached transaction, and prompts through the login.
+        let (res, pam, calls) = run(Some(PamReturnCode::CONV_ERR));
+        assert_eq!(res.unwrap(), AuthPath::Interactive);
+        assert_eq!(pam, "login");
+        assert_eq!(
+            calls,
+            vec!["cached authenticate", "cached end", "login authenticate"]
+        );
+
+        // Other failures do not prompt.
+        let (res, pam, calls) = run(Some(PamReturnCode::MAXTRIES));
+        assert!(res.is_err());
+        assert_eq!(pam, "login");
+        assert_eq!(calls, vec!["cached authenticate", "cached end"]);
+
+        let (res, pam, calls) = run(None);
+        assert_eq!(res.unwrap(), AuthPath::Interactive);
+        assert_eq!(pam, "login");
+        assert_eq!(calls, vec!["login authenticate"]);
+    }
+
+    /// Records the PAM calls of a session. open_session fails with each of
+    /// the codes in open_errors in turn before it succeeds.
+    #[derive(Default)]
+    struct MockCalls {
+        calls: Vec<String>,
+        open_errors: Vec<PamReturnCode>,
+        last_code: Option<PamReturnCode>,
+    }
+
+    impl SessionCalls for MockCalls {
+        fn acct_mgmt(&mut self) -> Result<(), PamError> {
+            self.calls.push("acct_mgmt".to_string());
+            Ok(())
+        }
+        fn setcred(&mut self, flags: PamFlag) -> Result<(), PamError> {
+            self.calls.push(format!("setcred {:?}", flags));
+            Ok(())
+        }
+        fn open_session(&mut self) -> Result<(), PamError> {
+            self.calls.push("open_session".to_string());
+            if self.open_errors.is_empty() {
+                self.last_code = None;
+                return Ok(());
+            }
+            let rc = self.open_errors.remove(0);
+            self.last_code = Some(rc);
+            Err(PamError::from_rc("pam_open_session", rc))
+        }
+        fn close_session(&mut self) -> Result<(), PamError> {
+            self.calls.push("close_session".to_string());
+            Ok(())
+        }
+        fn last_code(&self) -> PamReturnCode {
+            self.last_code.unwrap_or(PamReturnCode::SUCCESS)
+        }
+    }
+
+    /// Open a session that fails with the codes first, allowing the retries,
+    /// and return the result along with the number of attempts made.
+    fn open_with_errors(errors: Vec<PamReturnCode>, retries: u32) -> (Result<(), Error>, usize) {
+        let options = LoginOptions {
+            open_session_retries: retries,
+            ..Default::default()
+        };
+        let mut pam = MockCalls {
+            open_errors: errors,
+            ..Default::default()
+        };
+        let res = open_session(&mut pam, &options, &mut Timings::default());
+        let attempts = pam.calls.iter().filter(|c| *c == "open_session").count();
+        (res, attempts)
+    }
+
+    #[test]
+    fn open_session_retry_loop() {
+        use PamReturnCode::{SESSION_ERR, SYSTEM_ERR};
+
+        // Transient failures are retried until the session opens.
+        let (res, attempts) = open_with_errors(vec![SYSTEM_ERR, SESSION_ERR], 3);
+        res.unwrap();
+        assert_eq!(attempts, 3);
+
+        // The last failure is returned once the retries are used up.
+        let (res, attempts) =
+            open_with_errors(vec![SYSTEM_ERR, SYSTEM_ERR, SYSTEM_ERR, SESSION_ERR], 3);
+        assert_eq!(attempts, 4);
+        let last: Error = PamError::from_rc("pam_open_session", SESSION_ERR).into();
+        assert_eq!(res.unwrap_err().to_string(), last.to_string());
+        let (res, attempts) = open_with_errors(vec![SYSTEM_ERR], 0);
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+
+        // Other failures are not retried.
+        let (res, attempts) = open_with_errors(vec![SYSTEM_ERR, PamReturnCode::PERM_DENIED], 3);
+        assert!(res.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    fn session_calls(setcred_after_open: bool) -> Vec<String> {
+        let options = LoginOptions {
+            setcred_after_open,
+            ..Default::default()
+        };
+        let mut pam = MockCalls::default();
+        let mut timings = Timings::default();
+        authorize_account(&mut pam, &options, &mut timings).unwrap();
+        open_session(&mut pam, &options, &mut timings).unwrap();
+        close_session(&mut pam).unwrap();
+        pam.calls
+    }
+
+    #[test]
+    fn setcred_order() {
+        assert_eq!(
+            session_calls(false),
+            vec![
+                "acct_mgmt",
+                "setcred ESTABLISH_CRED",
+                "open_session",
+                "close_session",
+                "setcred DELETE_CRED"
+            ]
+        );
+        assert_eq!(
+            session_calls(true),
+            vec![
+                "acct_mgmt",
+                "open_session",
+                "setcred ESTABLISH_CRED",
+                "close_session",
+                "setcred DELETE_CRED"
+            ]
+        );
+    }
+
+    #[test]
+    fn session_timings() {
+        let log = Rc::new(RefCell::new(vec![]));
+        for &setcred_after_open in &[false, true] {
+            let options = LoginOptions {
+                setcred_after_open,
+                ..Default::default()
+            };
+            let mut pam = MockCalls::default();
+            let mut timings = Timings::default();
+            let mut auth = MockAuth::new("login", PamReturnCode::SUCCESS, &log);
+            authenticate_user(&mut auth, None, &mut timings).unwrap();
+            authorize_account(&mut pam, &options, &mut timings).unwrap();
+            open_session(&mut pam, &options, &mut timings).unwrap();
+            let phases: Vec<&str> = timings.0.iter().map(|t| t.phase.as_str()).collect();
+            let expected = if setcred_after_open {
+                vec!["authenticate", "acct_mgmt", "open_session", "setcred"]
+            } else {
+                vec!["authenticate", "acct_mgmt", "setcred", "open_session"]
+            };
+            assert_eq!(phases, expected);
+        }
+
+        // Phases that fail are not recorded.
+        let mut timings = Timings::default();
+        let mut auth = MockAuth::new("login", PamReturnCode::AUTH_ERR, &log);
+        assert!(authenticate_user(&mut auth, None, &mut timings).is_err());
+        assert!(timings.0.is_empty());
+    }
+
+    #[test]
+    fn service_name() {
+        assert!(validate_service("greetd").is_ok());
+        assert!(validate_service("greetd-greeter").is_ok());
+        assert!(validate_service("login").is_ok());
+        assert!(validate_service("my_service.v2").is_ok());
+
+        assert!(validate_service("").is_err());
+        assert!(validate_service(".").is_err());
+        assert!(validate_service("..").is_err());
+        assert!(validate_service("../../etc/shadow").is_err());
+        assert!(validate_service("/etc/passwd").is_err());
+        assert!(validate_service("pam.d/login").is_err());
+        assert!(validate_service(".hidden").is_err());
+        assert!(validate_service("greetd\0").is_err());
+        assert!(validate_service("greetd greeter").is_err());
+    }
+
+    fn run_command(command: &str, home: &str) -> std::process::Output {
+        std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .env("HOME", home)
+            .output()
+            .expect("unable to run shell")
+    }
+
+    #[test]
+    fn profile_command() {
+        let cmd = vec!["echo".to_string(), "ok".to_string()];
+        assert_eq!(session_command(&cmd, false, true), "exec echo ok");
+
+        let home = env::temp_dir().join(format!("greetd-profile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir(&home).unwrap();
+        let home = home.to_str().unwrap();
+
+        std::fs::write(format!("{}/.profile", home), "export GREETING=ok\n").unwrap();
+        let greet = vec!["echo".to_string(), "$GREETING".to_string()];
+        for strict in &[false, true] {
+            let out = run_command(&session_command(&greet, true, *strict), home);
+            assert!(out.status.success());
+            assert_eq!(out.stdout, b"ok\n");
+        }
+
+        std::fs::write(format!("{}/.profile", home), "false\n").unwrap();
+        let out = run_command(&session_command(&cmd, true, false), home);
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"ok\n");
+
+        let out = run_command(&session_command(&cmd, true, true), home);
+        assert!(!out.status.success());
+        assert!(out.stdout.is_empty());
+        assert!(String::from_utf8_lossy(&out.stderr).contains(".profile failed with status 1"));
+
+        // The status is that of the profile itself, and a profile that fails
+        // but recovers is not held against the session.
+        std::fs::write(format!("{}/.profile", home), "return 3\n").unwrap();
+        let out = run_command(&session_command(&cmd, true, true), home);
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains(".profile failed with status 3"));
+
+        std::fs::write(format!("{}/.profile", home), "false\ntrue\n").unwrap();
+        let out = run_command(&session_command(&cmd, true, true), home);
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"ok\n");
+
+        std::fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn process_name_truncation() {
+        assert_eq!(
+            process_name("greetd[{user}]", "john").as_bytes(),
+            b"greetd[john]"
+        );
+        assert_eq!(
+            process_name("greetd-session[{user}]", "john").as_bytes(),
+            b"greetd-session["
+        );
+        assert_eq!(process_name("{user}", "øøøøøøøø").as_bytes().len(), 14);
+        assert_eq!(process_name("gree\0td", "john").as_bytes(), b"greetd");
+    }
+
+    /// Run exec_session in a child, returning its exit status and the
+    /// reported exec status.
+    fn run_exec_session(args: &[&str], found: bool, fallback: Option<&[String]>) -> (i32, Vec<u8>) {
+        let args: Vec<CString> = args.iter().map(|a| CString::new(*a).unwrap()).collect();
+        let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                close(status_write).unwrap();
+                let status = read_exec_status(status_read).unwrap();
+                match waitpid(child, None).unwrap() {
+                    WaitStatus::Exited(_, code) => (code, status),
+                    s => panic!("unexpected wait status: {:?}", s),
+                }
+            }
+            ForkResult::Child => {
+                exec_session(&System, &args, found, fallback, &[], status_write);
+                unsafe { libc::_exit(99) };
+            }
+        }
+    }
+
+    #[test]
+    fn exec_fallback() {
+        let fallback = vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "exit 3".to_string(),
+        ];
+
+        assert_eq!(
+            run_exec_session(&["/bin/sh", "-c", "exit 2"], true, Some(&fallback)),
+            (2, vec![])
+        );
+        assert_eq!(
+            run_exec_session(&["/nonexistent/sh"], true, Some(&fallback)),
+            (3, vec![EXEC_STATUS_FALLBACK])
+        );
+        assert_eq!(
+            run_exec_session(&["/nonexistent/sh"], true, None),
+            (99, vec![EXEC_STATUS_FAILED])
+        );
+        assert_eq!(
+            run_exec_session(
+                &["/nonexistent/sh"],
+                true,
+                Some(&["/nonexistent/sh".to_string()])
+            ),
+            (99, vec![EXEC_STATUS_FALLBACK, EXEC_STATUS_FAILED])
+        );
+    }
+
+    #[test]
+    fn missing_session_program() {
+        let fallback = vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "exit 3".to_string(),
+        ];
+        let path = Some(OsStr::new("/usr/bin:/bin"));
+        let run = |cmd: &[String]| {
+            let command = session_command(cmd, false, false);
+            let found = program_found(cmd, path);
+            run_exec_session(&["/bin/sh", "-c", &command], found, Some(&fallback))
+        };
+
+        // The shell would start, but not the program.
+        for cmd in &["/nonexistent/sway --debug", "greetd-nonexistent-session"] {
+            let cmd = vec![cmd.to_string()];
+            assert!(!program_found(&cmd, path), "{:?}", cmd);
+            assert_eq!(run(&cmd), (3, vec![EXEC_STATUS_FALLBACK]), "{:?}", cmd);
+        }
+        assert!(!program_found(&["/etc/passwd".to_string()], path));
+        assert!(!program_found(&["/".to_string()], path));
+
+        assert_eq!(run(&["true".to_string()]), (0, vec![]));
+        assert_eq!(
+            run(&[
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "'exit 2'".to_string()
+            ]),
+            (2, vec![])
+        );
+
+        // Commands that are not a plain program are left to the shell.
+        assert!(program_found(&["FOO=1 sway".to_string()], path));
+        assert!(program_found(&["$HOME/bin/sway".to_string()], path));
+    }
+
+    /// Records the operations of the inner child instead of performing them.
+    struct MockChild {
+        fail_at: Option<&'static str>,
+        missing: Vec<&'static str>,
+        ops: RefCell<Vec<String>>,
+    }
+
+    impl MockChild {
+        fn new(fail_at: Option<&'static str>, missing: Vec<&'static str>) -> MockChild {
+            MockChild {
+                fail_at,
+                missing,
+                ops: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn step(&self, name: &'static str, op: String) -> nix::Result<()> {
+            self.ops.borrow_mut().push(op);
+            if self.fail_at == Some(name) {
+                Err(nix::Error::Sys(Errno::EPERM))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl PrivilegeDropper for MockChild {
+        fn initgroups(&self, user: &CStr, gid: Gid) -> nix::Result<()> {
+            self.step("initgroups", format!("initgroups {:?} {}", user, gid))
+        }
+        fn setgid(&self, gid: Gid) -> nix::Result<()> {
+            self.step("setgid", format!("setgid {}", gid))
+        }
+        fn setuid(&self, uid: Uid) -> nix::Result<()> {
+            self.step("setuid", format!("setuid {}", uid))
+        }
+        fn set_pdeathsig(&self, signal: i32) -> nix::Result<()> {
+            self.step("set_pdeathsig", format!("set_pdeathsig {}", signal))
+        }
+    }
+
+    impl Executor for MockChild {
+        fn execve(&self, path: &CStr, args: &[&CStr], env: &[&CStr]) -> nix::Error {
+            self.ops
+                .borrow_mut()
+                .push(format!("execve {:?} {:?} {:?}", path, args, env));
+            if self.missing.iter().any(|m| path.to_bytes() == m.as_bytes()) {
+                nix::Error::Sys(Errno::ENOENT)
+            } else {
+                nix::Error::Sys(Errno::EIO)
+            }
+        }
+    }
+
+    /// Run the inner child with the mock, for "exec sway" as john with the
+    /// specified PATH. The failure of the child and the status it reported
+    /// are returned.
+    fn run_child(child: &MockChild, fallback: Option<&[String]>, path: &str) -> (String, Vec<u8>) {
+        let user = CString::new("john").unwrap();
+        let options = LoginOptions {
+            fallback_cmd: fallback.map(|f| f.to_vec()),
+            inherit_sigpipe: true,
+            ..Default::default()
+        };
+        let cmd = vec!["sway".to_string()];
+        let setup = SessionSetup {
+            options: &options,
+            user: &user,
+            user_gid: Gid::from_raw(100),
+            uid: Uid::from_raw(1000),
+            gid: Gid::from_raw(100),
+            home: OsStr::new("/home/john"),
+            cgroup: None,
+            cpus: None,
+            log_file: None,
+            cmd: &cmd,
+            command: "exec sway",
+        };
+        let mut env = PamEnv::default();
+        env.set("PATH", path).unwrap();
+
+        let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let err = exec_inner_child(child, &setup, &mut env, status_write);
+        close(status_write).unwrap();
+        (err, read_exec_status(status_read).unwrap())
+    }
+
+    #[test]
+    fn child_sequence() {
+        let child = MockChild::new(None, vec![]);
+        let (err, status) = run_child(&child, None, "/usr/bin");
+        assert_eq!(err, "unable to exec: EIO: I/O error");
+        assert_eq!(status, vec![EXEC_STATUS_FAILED]);
+        assert_eq!(
+            *child.ops.borrow(),
+            vec![
+                "initgroups \"john\" 100".to_string(),
+                "setgid 100".to_string(),
+                "setuid 1000".to_string(),
+                format!("set_pdeathsig {}", libc::SIGTERM),
+                r#"execve "/bin/sh" ["/bin/sh", "-c", "exec sway"] ["PATH=/usr/bin"]"#.to_string(),
+            ]
+        );
+
+        // A shell that cannot be executed starts the fallback.
+        let fallback = vec!["/bin/bash".to_string()];
+        let dir = std::env::temp_dir().join(format!("greetd-child-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("sway"), "").unwrap();
+        std::fs::set_permissions(dir.join("sway"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = dir.to_str().unwrap();
+        let child = MockChild::new(None, vec!["/bin/sh"]);
+        let (_, status) = run_child(&child, Some(&fallback), path);
+        assert_eq!(status, vec![EXEC_STATUS_FALLBACK, EXEC_STATUS_FAILED]);
+        assert_eq!(child.ops.borrow().len(), 6);
+        assert_eq!(
+            child.ops.borrow()[5],
+            format!(r#"execve "/bin/bash" ["/bin/bash"] ["PATH={}"]"#, path)
+        );
+
+        // So does a program that is not on the PATH, without trying.
+        let child = MockChild::new(None, vec![]);
+        let (_, status) = run_child(&child, Some(&fallback), "/nonexistent");
+        assert_eq!(status, vec![EXEC_STATUS_FALLBACK, EXEC_STATUS_FAILED]);
+        assert_eq!(
+            child.ops.borrow()[4..],
+            [r#"execve "/bin/bash" ["/bin/bash"] ["PATH=/nonexistent"]"#.to_string()]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn child_primary_group() {
+        // The session runs with the chosen group, but keeps the groups of
+        // the user, including its own primary group.
+        let child = MockChild::new(None, vec![]);
+        let user = CString::new("john").unwrap();
+        let res = drop_privileges(
+            &child,
+            &user,
+            Gid::from_raw(100),
+            Uid::from_raw(1000),
+            Gid::from_raw(200),
+            libc::SIGTERM,
+        );
+        assert!(res.is_ok());
+        assert_eq!(
+            child.ops.borrow()[..3],
+            [
+                "initgroups \"john\" 100".to_string(),
+                "setgid 200".to_string(),
+                "setuid 1000".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn primary_group() {
+        let user = User::new(1000, "john", 100);
+        let lookup = |name: &str| match name {
+            "users" => Some(Group::new(100, "users")),
+            "project" => Some(Group::new(200, "project").add_member("john")),
+            "wheel" => Some(Group::new(10, "wheel").add_member("jane")),
+            _ => None,
+        };
+
+        assert_eq!(
+            session_group(&user, None, lookup).unwrap(),
+            Gid::from_raw(100)
+        );
+        assert_eq!(
+            session_group(&user, Some("users"), lookup).unwrap(),
+            Gid::from_raw(100)
+        );
+        assert_eq!(
+            session_group(&user, Some("project"), lookup).unwrap(),
+            Gid::from_raw(200)
+        );
+        assert!(session_group(&user, Some("wheel"), lookup).is_err());
+        assert!(session_group(&user, Some("nonexistent"), lookup).is_err());
+    }
+
+    #[test]
+    fn child_privilege_failure() {
+        // Nothing may run after a failed step, least of all the session.
+        for (step, len) in &[
+            ("initgroups", 1),
+            ("setgid", 2),
+            ("setuid", 3),
+            ("set_pdeathsig", 4),
+        ] {
+            let child = MockChild::new(Some(step), vec![]);
+            let (err, status) = run_child(&child, None, "/usr/bin");
+            assert!(!err.starts_with("unable to exec"), "{}", step);
+            assert!(status.is_empty(), "{}", step);
+            assert_eq!(child.ops.borrow().len(), *len, "{}", step);
+        }
+    }
+
+    #[test]
+    fn sigpipe() {
+        let echo = CString::new("/bin/echo").unwrap();
+        let hello = CString::new("hello").unwrap();
+        for &reset in &[false, true] {
+            let (read, write) = nix::unistd::pipe().unwrap();
+            close(read).unwrap();
+            match fork().unwrap() {
+                ForkResult::Parent { child } => {
+                    close(write).unwrap();
+                    let status = waitpid(child, None).unwrap();
+                    if reset {
+                        assert_eq!(status, WaitStatus::Signaled(child, Signal::SIGPIPE, false));
+                    } else {
+                        assert!(matches!(status, WaitStatus::Exited(_, code) if code != 0));
+                    }
+                }
+                ForkResult::Child => {
+                    nix::unistd::dup2(write, 1).unwrap();
+                    if reset {
+                        reset_sigpipe().unwrap();
+                    }
+                    let _ = execve(&echo, &[&echo, &hello], &[]);
+                    unsafe { libc::_exit(99) };
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn log_file() {
+        let path = env::temp_dir().join(format!("greetd-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sh = CString::new("/bin/sh").unwrap();
+        let c = CString::new("-c").unwrap();
+        let cmd = CString::new("echo out; echo err >&2").unwrap();
+
+        for &append in &[false, true, false] {
+            let log = open_log(&path, append, getuid(), getgid()).unwrap();
+            match fork().unwrap() {
+                ForkResult::Parent { child } => {
+                    assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+                }
+                ForkResult::Child => {
+                    connect_log(&log).unwrap();
+                    let _ = execve(&sh, &[&sh, &c, &cmd], &[]);
+                    unsafe { libc::_exit(99) };
+                }
+            }
+            let expected = if append {
+                "out\nerr\nout\nerr\n"
+            } else {
+                "out\nerr\n"
+            };
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        }
+
+        let link = path.with_extension("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(open_log(&link, true, getuid(), getgid()).is_err());
+        std::fs::remove_file(&link).unwrap();
+
+        // Nor are symlinks to directories anywhere along the path.
+        let dir = path.with_extension("dir");
+        let _ = std::fs::remove_file(&dir);
+        std::os::unix::fs::symlink(env::temp_dir(), &dir).unwrap();
+        let name = path.file_name().unwrap();
+        assert!(open_log(&dir.join(name), true, getuid(), getgid()).is_err());
+        assert!(open_log(&dir.join("..").join(name), true, getuid(), getgid()).is_err());
+        std::fs::remove_file(&dir).unwrap();
+
+        // A hardlink to another file is neither truncated nor handed over.
+        std::fs::hard_link(&path, &link).unwrap();
+        assert!(open_log(&link, false, getuid(), getgid()).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "out\nerr\n");
+        std::fs::remove_file(&link).unwrap();
+
+        // Nor is a file of another user.
+        if getuid().is_root() {
+            nix::unistd::chown(&path, Some(Uid::from_raw(65534)), None).unwrap();
+            assert!(open_log(&path, false, getuid(), getgid()).is_err());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "out\nerr\n");
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(open_log(Path::new("/dev/null"), true, getuid(), getgid()).is_err());
+        assert!(open_log(&env::temp_dir(), true, getuid(), getgid()).is_err());
+    }
+
+    #[test]
+    fn non_utf8_home() {
+        let home = OsStr::from_bytes(b"/home/j\xf6rg");
+        let env: Vec<CString> = user_env(OsStr::new("jorg"), home, OsStr::new("/bin/sh"), home)
+            .iter()
+            .map(|e| CString::new(e.as_bytes()).unwrap())
+            .collect();
+        assert!(env.iter().any(|e| e.as_bytes() == b"HOME=/home/j\xf6rg"));
+
+        // The session sees the home directory byte for byte.
+        let (out_read, out_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let (status_read, status_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let args: Vec<CString> = ["/bin/sh", "-c", "printf %s \"$HOME\""]
+            .iter()
+            .map(|a| CString::new(*a).unwrap())
+            .collect();
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                close(out_write).unwrap();
+                close(status_write).unwrap();
+                assert!(read_exec_status(status_read).unwrap().is_empty());
+                let out = read_exec_status(out_read).unwrap();
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+                assert_eq!(out, b"/home/j\xf6rg");
+            }
+            ForkResult::Child => {
+                dup2(out_write, 1).unwrap();
+                let env: Vec<&CStr> = env.iter().map(|e| e.as_c_str()).collect();
+                exec_session(&System, &args, true, None, &env, status_write);
+                unsafe { libc::_exit(99) };
+            }
+        }
+    }
+
+    /// Enter a home directory in a forked child, optionally as another user,
+    /// as the working directory is shared by the threads of the tests.
+    fn enter_home_in_child(home: &Path, uid: Option<Uid>) -> Vec<u8> {
+        let (out_read, out_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                close(out_write).unwrap();
+                let out = read_exec_status(out_read).unwrap();
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+                out
+            }
+            ForkResult::Child => {
+                if let Some(uid) = uid {
+                    nix::unistd::setgid(Gid::from_raw(uid.as_raw())).unwrap();
+                    nix::unistd::setuid(uid).unwrap();
+                }
+                let code = match enter_home(home.as_os_str()) {
+                    Ok(pwd) if env::current_dir().unwrap() == Path::new(pwd) => {
+                        let _ = write(out_write, pwd.as_bytes());
+                        0
+                    }
+                    _ => 1,
+                };
+                unsafe { libc::_exit(code) };
+            }
+        }
+    }
+
+    #[test]
+    fn home_fallback() {
+        let dir = env::temp_dir().join(format!("greetd-home-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(enter_home_in_child(&dir, None), dir.as_os_str().as_bytes());
+
+        // Home directories need not be valid UTF-8.
+        let latin1 = dir.join(OsStr::from_bytes(b"j\xf6rg"));
+        std::fs::create_dir(&latin1).unwrap();
+        assert_eq!(
+            enter_home_in_child(&latin1, None),
+            latin1.as_os_str().as_bytes()
+        );
+
+        let missing = dir.join("missing");
+        assert_eq!(enter_home_in_child(&missing, None), b"/");
+
+        let file = dir.join("file");
+        std::fs::write(&file, "").unwrap();
+        assert_eq!(enter_home_in_child(&file, None), b"/");
+
+        // A home the user cannot traverse to. Root can, so the check is done
+        // as nobody when running as root.
+        let locked = dir.join("locked");
+        let home = locked.join("home");
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::set_permissions(&home, std::fs::Permissions::from_mode(0o777)).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o700)).unwrap();
+        if getuid().is_root() {
+            nix::unistd::chown(&home, Some(Uid::from_raw(65534)), None).unwrap();
+            assert_eq!(
+                enter_home_in_child(&home, None),
+                home.as_os_str().as_bytes()
+            );
+            assert_eq!(enter_home_in_child(&home, Some(Uid::from_raw(65534))), b"/");
+        } else {
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+            assert_eq!(enter_home_in_child(&home, None), b"/");
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o700)).unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
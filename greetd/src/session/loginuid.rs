@@ -0,0 +1,39 @@
+use std::{fs, io, path::Path};
+
+use nix::unistd::Uid;
+
+pub const LOGINUID_PATH: &str = "/proc/self/loginuid";
+
+/// The loginuid of a process that has not been assigned one.
+const LOGINUID_UNSET: &str = "4294967295";
+
+/// Set the audit loginuid of the current process, which is inherited by its
+/// children. If the loginuid has already been set, e.g. by pam_loginuid, it is
+/// left alone and false is returned.
+pub fn set_loginuid(path: &Path, uid: Uid) -> io::Result<bool> {
+    if fs::read_to_string(path)?.trim() != LOGINUID_UNSET {
+        return Ok(false);
+    }
+    fs::write(path, uid.as_raw().to_string())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loginuid() {
+        let path = std::env::temp_dir().join(format!("greetd-loginuid-{}", std::process::id()));
+
+        fs::write(&path, LOGINUID_UNSET).unwrap();
+        assert!(set_loginuid(&path, Uid::from_raw(1000)).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1000");
+
+        assert!(!set_loginuid(&path, Uid::from_raw(1001)).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1000");
+
+        fs::remove_file(&path).unwrap();
+        assert!(set_loginuid(&path, Uid::from_raw(1000)).is_err());
+    }
+}
@@ -1,4 +1,12 @@
+mod cgroup;
+mod child;
 pub mod conv;
 pub mod interface;
+mod keyring;
+pub mod login;
+mod loginuid;
 mod prctl;
+mod ttyowner;
+mod userenv;
+mod utmp;
 pub mod worker;
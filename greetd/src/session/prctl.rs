@@ -1,10 +1,17 @@
+use std::ffi::CStr;
+
 use nix::{errno::Errno, Result};
 
 pub const PRCTL_SET_PDEATHSIG: i32 = 1;
+pub const PRCTL_SET_NAME: i32 = 15;
+
+/// The longest process name accepted by PR_SET_NAME, excluding the NUL.
+pub const PROCESS_NAME_MAX: usize = 15;
 
 #[allow(non_camel_case_types)]
-pub enum PrctlOption {
+pub enum PrctlOption<'a> {
     SET_PDEATHSIG(i32),
+    SET_NAME(&'a CStr),
 }
 
 pub fn prctl(option: PrctlOption) -> Result<()> {
@@ -12,6 +19,9 @@ pub fn prctl(option: PrctlOption) -> Result<()> {
         PrctlOption::SET_PDEATHSIG(sig) => unsafe {
             libc::prctl(PRCTL_SET_PDEATHSIG, sig, 0, 0, 0)
         },
+        PrctlOption::SET_NAME(name) => unsafe {
+            libc::prctl(PRCTL_SET_NAME, name.as_ptr(), 0, 0, 0)
+        },
     })
     .map(drop)
 }
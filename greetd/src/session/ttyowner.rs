@@ -0,0 +1,121 @@
+use std::{
+    fs, io,
+    os::unix::fs::{chown, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::{getpid, Pid, Uid};
+
+/// The ownership of a terminal device as it was before it was handed to the
+/// user of a session, for systems where logind does not grant access to it.
+pub struct TtyOwner {
+    path: PathBuf,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    owner: Pid,
+    restored: bool,
+}
+
+impl TtyOwner {
+    /// Make the user the owner of the terminal device, and set its mode. The
+    /// group is left alone, so that a mode such as 0o620 lets the tty group
+    /// write to the terminal, as with login(1). Only permission bits may be
+    /// set.
+    pub fn take(path: &Path, uid: Uid, mode: u32) -> io::Result<TtyOwner> {
+        if mode & !0o777 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid terminal mode: {:o}", mode),
+            ));
+        }
+        let meta = fs::metadata(path)?;
+        let owner = TtyOwner {
+            path: path.to_path_buf(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mode: meta.mode() & 0o7777,
+            owner: getpid(),
+            restored: false,
+        };
+        chown(path, Some(uid.as_raw()), None)?;
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+            let _ = owner.restore();
+            return Err(e);
+        }
+        Ok(owner)
+    }
+
+    /// Return the terminal device to its previous owner and mode.
+    pub fn restore(mut self) -> io::Result<()> {
+        self.reset()
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.restored = true;
+        chown(&self.path, Some(self.uid), Some(self.gid))?;
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(self.mode))
+    }
+}
+
+impl Drop for TtyOwner {
+    /// Restore a terminal that was abandoned without being restored, such as
+    /// by a panic. Forked children inherit the owner, so only the process
+    /// that took the terminal restores it.
+    fn drop(&mut self) {
+        if !self.restored && getpid() == self.owner {
+            if let Err(e) = self.reset() {
+                eprintln!("session: unable to restore terminal ownership: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::getuid;
+
+    #[test]
+    fn take_restore() {
+        let path = std::env::temp_dir().join(format!("greetd-tty-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let before = fs::metadata(&path).unwrap();
+
+        // Only root can give the file away.
+        let uid = if getuid().is_root() {
+            Uid::from_raw(65534)
+        } else {
+            getuid()
+        };
+        let owner = TtyOwner::take(&path, uid, 0o620).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.uid(), uid.as_raw());
+        assert_eq!(meta.gid(), before.gid());
+        assert_eq!(meta.mode() & 0o7777, 0o620);
+
+        owner.restore().unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.uid(), before.uid());
+        assert_eq!(meta.gid(), before.gid());
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+
+        // An abandoned terminal is restored as well.
+        drop(TtyOwner::take(&path, uid, 0o620).unwrap());
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.uid(), before.uid());
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+
+        for &mode in &[0o4755, 0o2620, 0o1777, 0o10620] {
+            assert_eq!(
+                TtyOwner::take(&path, uid, mode).err().map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidInput)
+            );
+        }
+        assert_eq!(fs::metadata(&path).unwrap().uid(), before.uid());
+
+        fs::remove_file(&path).unwrap();
+        assert!(TtyOwner::take(&path, uid, 0o620).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    ffi::{CStr, OsStr},
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::{fs::MetadataExt, io::FromRawFd},
+    path::Path,
+};
+
+use enquote::unquote;
+use nix::{
+    fcntl::OFlag,
+    sys::wait::waitpid,
+    unistd::{close, fork, pipe2, ForkResult, Gid, Uid},
+};
+
+use super::child::{drop_privileges, System};
+use crate::error::Error;
+
+/// Parse an environment.d style file, consisting of KEY=VALUE assignments,
+/// comments and empty lines. Values may be quoted.
+pub fn parse(contents: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => continue,
+        };
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            continue;
+        }
+        let value = match value.chars().next() {
+            Some('"') | Some('\'') => match unquote(value) {
+                Ok(v) => v,
+                Err(_) => continue,
+            },
+            _ => value.to_string(),
+        };
+        set(&mut vars, key.to_string(), value);
+    }
+    vars
+}
+
+/// Set a variable, replacing any earlier assignment.
+fn set(vars: &mut Vec<(String, String)>, key: String, value: String) {
+    match vars.iter_mut().find(|(k, _)| *k == key) {
+        Some(var) => var.1 = value,
+        None => vars.push((key, value)),
+    }
+}
+
+/// Load all *.conf files in the directory in lexical order, with later files
+/// overriding earlier ones. Files and directories not owned by the specified
+/// user are ignored, as they could have been placed there by someone else.
+pub fn load(dir: &Path, uid: Uid) -> io::Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    if fs::metadata(dir)?.uid() != uid.as_raw() {
+        eprintln!("session: ignoring {}: not owned by user", dir.display());
+        return Ok(vars);
+    }
+
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension() == Some(OsStr::new("conf")))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let meta = fs::metadata(&path)?;
+        if !meta.is_file() || meta.uid() != uid.as_raw() {
+            eprintln!(
+                "session: ignoring {}: not a file owned by user",
+                path.display()
+            );
+            continue;
+        }
+        for (key, value) in parse(&fs::read_to_string(&path)?) {
+            set(&mut vars, key, value);
+        }
+    }
+    Ok(vars)
+}
+
+/// Load the environment files in a child with the groups, GID and UID of the
+/// target user, so that nothing the user could not read themselves is read.
+/// Changing only the effective IDs would leave the supplementary groups of
+/// greetd in place.
+pub fn load_as_user(
+    dir: &Path,
+    user: &CStr,
+    user_gid: Gid,
+    uid: Uid,
+    gid: Gid,
+) -> Result<Vec<(String, String)>, Error> {
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+    match fork() {
+        Ok(ForkResult::Parent { child }) => {
+            let _ = close(write_fd);
+            let mut data = Vec::new();
+            let res = unsafe { File::from_raw_fd(read_fd) }.read_to_end(&mut data);
+            waitpid(child, None)?;
+            res?;
+            let res: Result<Vec<(String, String)>, String> = serde_json::from_slice(&data)
+                .map_err(|e| format!("invalid environment from reader: {}", e))?;
+            Ok(res?)
+        }
+        Ok(ForkResult::Child) => {
+            let _ = close(read_fd);
+            let res = drop_privileges(&System, user, user_gid, uid, gid, libc::SIGKILL)
+                .and_then(|_| load(dir, uid).map_err(|e| e.to_string()));
+            let mut file = unsafe { File::from_raw_fd(write_fd) };
+            let code = match file.write_all(&serde_json::to_vec(&res).unwrap_or_default()) {
+                Ok(_) => 0,
+                Err(_) => 1,
+            };
+            unsafe { libc::_exit(code) };
+        }
+        Err(e) => {
+            let _ = close(read_fd);
+            let _ = close(write_fd);
+            Err(e.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::getuid;
+    use std::{
+        ffi::CString,
+        os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    };
+
+    fn vars(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_file() {
+        assert_eq!(
+            parse(
+                "
+# comment
+EDITOR=vim
+PATH = \"/opt/bin:/usr/bin\"
+
+invalid line
+BAD-KEY=1
+EDITOR='nano'
+"
+            ),
+            vars(&[("EDITOR", "nano"), ("PATH", "/opt/bin:/usr/bin")])
+        );
+    }
+
+    #[test]
+    fn load_dir() {
+        let dir = std::env::temp_dir().join(format!("greetd-userenv-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("20-editor.conf"), "EDITOR=nano\n").unwrap();
+        fs::write(dir.join("10-editor.conf"), "EDITOR=vim\nPAGER=less\n").unwrap();
+        fs::write(dir.join("README"), "EDITOR=ed\n").unwrap();
+
+        assert_eq!(
+            load(&dir, getuid()).unwrap(),
+            vars(&[("EDITOR", "nano"), ("PAGER", "less")])
+        );
+
+        let other = Uid::from_raw(getuid().as_raw() + 1);
+        assert_eq!(load(&dir, other).unwrap(), vec![]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(load(&dir, getuid()).is_err());
+    }
+
+    #[test]
+    fn load_dir_as_user() {
+        if !getuid().is_root() {
+            return;
+        }
+        let nobody = users::get_user_by_uid(65534).unwrap();
+        let name = CString::new(nobody.name().as_bytes()).unwrap();
+        let (uid, gid) = (
+            Uid::from_raw(65534),
+            Gid::from_raw(nobody.primary_group_id()),
+        );
+
+        // Only the supplementary group of root may traverse the parent.
+        let root = std::env::temp_dir().join(format!("greetd-userenv-as-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("environment.d");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("10-editor.conf"), "EDITOR=vim\n").unwrap();
+        for path in &[dir.clone(), dir.join("10-editor.conf")] {
+            nix::unistd::chown(path, Some(uid), Some(gid)).unwrap();
+        }
+        fs::set_permissions(&root, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            load_as_user(&dir, &name, gid, uid, gid).unwrap(),
+            vars(&[("EDITOR", "vim")])
+        );
+
+        nix::unistd::chown(&root, None, Some(Gid::from_raw(0))).unwrap();
+        fs::set_permissions(&root, fs::Permissions::from_mode(0o710)).unwrap();
+        assert!(load_as_user(&dir, &name, gid, uid, gid).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    ffi::{CString, OsStr},
+    io, mem,
+    os::{raw::c_char, unix::ffi::OsStrExt},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::unistd::Pid;
+
+pub const UTMP_PATH: &str = "/var/run/utmp";
+pub const WTMP_PATH: &str = "/var/log/wtmp";
+
+extern "C" {
+    fn updwtmpx(wtmpx_file: *const c_char, utmpx: *const libc::utmpx);
+}
+
+/// Copy a string into a fixed-size utmp field, truncating it if necessary.
+/// The fields need not be NUL-terminated.
+fn copy_field(field: &mut [c_char], value: &[u8]) {
+    for (dst, &src) in field.iter_mut().zip(value) {
+        *dst = src as c_char;
+    }
+}
+
+/// Build a utmp entry for the specified terminal line, such as "tty1".
+fn entry(ut_type: libc::c_short, line: &str, user: &OsStr, pid: Pid) -> libc::utmpx {
+    let mut ut: libc::utmpx = unsafe { mem::zeroed() };
+    ut.ut_type = ut_type;
+    ut.ut_pid = pid.as_raw();
+    copy_field(&mut ut.ut_line, line.as_bytes());
+    // Like login(1), identify the entry by the end of the line name.
+    let id_len = ut.ut_id.len();
+    copy_field(
+        &mut ut.ut_id,
+        &line.as_bytes()[line.len().saturating_sub(id_len)..],
+    );
+    copy_field(&mut ut.ut_user, user.as_bytes());
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    ut.ut_tv.tv_sec = now.as_secs() as _;
+    ut.ut_tv.tv_usec = now.subsec_micros() as _;
+    ut
+}
+
+fn write(utmp: &Path, wtmp: &Path, ut: &libc::utmpx) -> io::Result<()> {
+    let utmp = CString::new(utmp.as_os_str().as_bytes())?;
+    let wtmp = CString::new(wtmp.as_os_str().as_bytes())?;
+    unsafe {
+        if libc::utmpxname(utmp.as_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::setutxent();
+        let res = libc::pututxline(ut);
+        let err = io::Error::last_os_error();
+        libc::endutxent();
+        if res.is_null() {
+            return Err(err);
+        }
+        updwtmpx(wtmp.as_ptr(), ut);
+    }
+    Ok(())
+}
+
+/// Record the login of a user on the specified terminal line.
+pub fn login(utmp: &Path, wtmp: &Path, line: &str, user: &OsStr, pid: Pid) -> io::Result<()> {
+    write(utmp, wtmp, &entry(libc::USER_PROCESS, line, user, pid))
+}
+
+/// Record the end of the login on the specified terminal line.
+pub fn logout(utmp: &Path, wtmp: &Path, line: &str, pid: Pid) -> io::Result<()> {
+    write(
+        utmp,
+        wtmp,
+        &entry(libc::DEAD_PROCESS, line, OsStr::new(""), pid),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn read_entries(path: &Path) -> Vec<(libc::c_short, libc::pid_t, String, String)> {
+        let data = fs::read(path).unwrap();
+        let size = mem::size_of::<libc::utmpx>();
+        assert_eq!(data.len() % size, 0);
+        data.chunks(size)
+            .map(|chunk| {
+                let ut: libc::utmpx =
+                    unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const libc::utmpx) };
+                let field = |f: &[c_char]| {
+                    let bytes: Vec<u8> =
+                        f.iter().map(|&c| c as u8).take_while(|&c| c != 0).collect();
+                    String::from_utf8_lossy(&bytes).into_owned()
+                };
+                (
+                    ut.ut_type,
+                    ut.ut_pid,
+                    field(&ut.ut_line),
+                    field(&ut.ut_user),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn login_logout() {
+        let dir = std::env::temp_dir().join(format!("greetd-utmp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let utmp = dir.join("utmp");
+        let wtmp = dir.join("wtmp");
+        fs::write(&utmp, "").unwrap();
+        fs::write(&wtmp, "").unwrap();
+
+        let pid = Pid::from_raw(1234);
+        login(&utmp, &wtmp, "tty1", OsStr::new("john"), pid).unwrap();
+        assert_eq!(
+            read_entries(&utmp),
+            vec![(
+                libc::USER_PROCESS,
+                1234,
+                "tty1".to_string(),
+                "john".to_string()
+            )]
+        );
+
+        logout(&utmp, &wtmp, "tty1", pid).unwrap();
+        assert_eq!(
+            read_entries(&utmp),
+            vec![(libc::DEAD_PROCESS, 1234, "tty1".to_string(), "".to_string())]
+        );
+        assert_eq!(
+            read_entries(&wtmp),
+            vec![
+                (
+                    libc::USER_PROCESS,
+                    1234,
+                    "tty1".to_string(),
+                    "john".to_string()
+                ),
+                (libc::DEAD_PROCESS, 1234, "tty1".to_string(), "".to_string()),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
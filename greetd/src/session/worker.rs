@@ -1,18 +1,42 @@
-use std::{env, ffi::CString, os::unix::net::UnixDatagram};
+use std::{
+    any::Any,
+    fmt, io,
+    mem::ManuallyDrop,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixDatagram,
+    },
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    pin::Pin,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use nix::{
-    sys::wait::waitpid,
-    unistd::{execve, fork, initgroups, setgid, setsid, setuid, ForkResult, Gid, Uid},
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{socketpair, AddressFamily, SockFlag, SockType},
+    unistd::{getpgid, getpgrp, getpid, Pid},
 };
-use pam_sys::{PamFlag, PamItemType};
 use serde::{Deserialize, Serialize};
-use users::os::unix::UserExt;
 
 use super::{
-    conv::SessionConv,
+    cgroup::{self, CGROUP_ROOT},
+    conv::{PollQueue, PollingConv, SessionConv},
+    login::{self, AuthPath, Login},
+    loginuid::LOGINUID_PATH,
     prctl::{prctl, PrctlOption},
+    utmp::UTMP_PATH,
+};
+use crate::{
+    error::Error,
+    pam::{
+        converse::{ConvEncoding, Converse},
+        env::PamEnv,
+        session::end_abandoned,
+    },
+    terminal::MAX_VT,
 };
-use crate::{error::Error, pam::session::PamSession, terminal};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum AuthMessageType {
@@ -22,6 +46,36 @@ pub enum AuthMessageType {
     Error,
 }
 
+impl AuthMessageType {
+    /// Whether a message of this style asks for an answer.
+    pub fn is_prompt(&self) -> bool {
+        match self {
+            AuthMessageType::Visible | AuthMessageType::Secret => true,
+            AuthMessageType::Info | AuthMessageType::Error => false,
+        }
+    }
+
+    /// The severity of a message of this style.
+    pub fn severity(&self) -> Severity {
+        match self {
+            AuthMessageType::Visible | AuthMessageType::Secret => Severity::Notice,
+            AuthMessageType::Info => Severity::Info,
+            AuthMessageType::Error => Severity::Error,
+        }
+    }
+}
+
+/// The severity of a PAM message, for filtering by the parent and by
+/// greeters, which are told it along with the message. Prompts need
+/// attention, but are not errors.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Notice,
+    Info,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum TerminalMode {
     Terminal {
@@ -32,6 +86,231 @@ pub enum TerminalMode {
     Stdin,
 }
 
+impl TerminalMode {
+    /// Check that the VT is one the kernel could provide, so that the
+    /// terminal is not opened and configured in vain.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            TerminalMode::Terminal { vt, .. } if *vt < 1 || *vt > MAX_VT => {
+                Err(Error::ProtocolError(format!(
+                    "invalid VT {}, must be between 1 and {}",
+                    vt, MAX_VT
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An opaque token provided by the greeter, which is handed to the session
+/// through the GREETD_SESSION_COOKIE environment variable. The worker never
+/// logs it or exposes it to PAM, but protecting it beyond that is the
+/// responsibility of the greeter that generated it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SessionCookie(pub String);
+
+impl SessionCookie {
+    const ENV_NAME: &'static str = "GREETD_SESSION_COOKIE";
+
+    pub fn apply(&self, env: &mut PamEnv) -> Result<(), Error> {
+        Ok(env.set(Self::ENV_NAME, &self.0)?)
+    }
+}
+
+impl fmt::Debug for SessionCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionCookie(<redacted>)")
+    }
+}
+
+/// How long a phase of a login took.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: String,
+    pub micros: u64,
+}
+
+/// How long each completed phase of a login took, in the order they were
+/// completed, for tuning PAM stacks and terminal setup.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Timings(pub Vec<PhaseTiming>);
+
+impl Timings {
+    /// Record the completion of a phase that began at start.
+    pub fn record(&mut self, phase: &str, start: Instant) {
+        self.0.push(PhaseTiming {
+            phase: phase.to_string(),
+            micros: start.elapsed().as_micros() as u64,
+        });
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, timing) in self.0.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {}us", timing.phase, timing.micros)?;
+        }
+        Ok(())
+    }
+}
+
+/// Optional parameters for a login, carried by InitiateLogin.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LoginOptions {
+    /// The bare session name, exported as XDG_SESSION_DESKTOP.
+    pub session_desktop: Option<String>,
+    /// How many times to retry open_session on transient failures.
+    pub open_session_retries: u32,
+    /// The delay before the first open_session retry, doubled for every
+    /// subsequent attempt.
+    pub open_session_backoff_ms: u64,
+    /// A token to pass to the session, and only to the session.
+    pub session_cookie: Option<SessionCookie>,
+    /// Queue PAM messages until the parent polls for them with PollMessages,
+    /// instead of sending each message as a PamMessage.
+    pub poll_conversation: bool,
+    /// Set the audit loginuid of the session if PAM did not already do so.
+    pub set_loginuid: bool,
+    /// Run the session in a cgroup of this name, created in the greetd
+    /// hierarchy of a cgroup v2 mount.
+    pub cgroup: Option<String>,
+    /// Limits for the session cgroup as pairs of interface file and value,
+    /// such as ("memory.max", "2G").
+    pub cgroup_limits: Vec<(String, String)>,
+    /// A directory of environment.d style files to load into the session
+    /// environment. Relative paths are resolved from the home directory.
+    pub user_env_dir: Option<String>,
+    /// Abort the session if sourcing a profile fails, rather than running
+    /// the command regardless.
+    pub profile_strict: bool,
+    /// Change to the home directory as the target user rather than as root,
+    /// so that inaccessible home directories are noticed.
+    pub chdir_as_user: bool,
+    /// Write utmp and wtmp records for sessions on a terminal, for login
+    /// accounting on systems without logind.
+    pub write_utmp: bool,
+    /// How to decode PAM messages that are not valid UTF-8.
+    pub conv_encoding: ConvEncoding,
+    /// A name for the session worker as shown by ps and top, in which
+    /// "{user}" is replaced by the name of the user. Names are truncated to
+    /// 15 bytes.
+    pub process_name: Option<String>,
+    /// A command to run instead if the session command cannot be executed,
+    /// such as a plain shell. The first element is the path of the program.
+    pub fallback_cmd: Option<Vec<String>>,
+    /// Leave SIGPIPE ignored in the session as it is in greetd, rather than
+    /// restoring the default disposition.
+    pub inherit_sigpipe: bool,
+    /// Give the session a new anonymous session keyring, for PAM stacks that
+    /// do not include pam_keyinit.
+    pub new_session_keyring: bool,
+    /// A file to connect the standard file descriptors of the session to,
+    /// for sessions without a terminal. The file is owned by the user, and its
+    /// path may not contain symlinks or "..".
+    pub log_file: Option<String>,
+    /// Append to the log file rather than truncating it.
+    pub log_append: bool,
+    /// How many more times to look up the user if it does not exist after
+    /// open_session, for PAM stacks that provision users asynchronously.
+    pub user_lookup_retries: u32,
+    /// The delay between user lookups.
+    pub user_lookup_delay_ms: u64,
+    /// Pin the session to these CPUs, which must all be in the affinity of
+    /// greetd itself. The affinity is further confined to the cpuset
+    /// of the session cgroup, if any, and cannot be set if the two are
+    /// disjoint.
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// A PAM service to first try to authenticate with without prompting,
+    /// such as one with a credential cache module. If that succeeds, the
+    /// login continues in the transaction of this service, so that its
+    /// account and session stacks see the credentials its auth stack
+    /// obtained, and the transaction of the login service is ended unused.
+    /// This service therefore decides the account checks and session setup
+    /// of every login it authenticates: it must provide account and session
+    /// stacks at least as strict as those of the login service, as those
+    /// are not run.
+    pub auth_service_cached: Option<String>,
+    /// Establish credentials after open_session rather than before, for
+    /// modules such as some Kerberos configurations that need the session
+    /// to be open first. Modules that set up the session from credentials,
+    /// such as ones mounting an encrypted home directory, need the default.
+    pub setcred_after_open: bool,
+    /// The keyboard layout selected in the greeter, exported as
+    /// XKB_DEFAULT_LAYOUT.
+    pub xkb_layout: Option<String>,
+    /// The keyboard layout variant, exported as XKB_DEFAULT_VARIANT.
+    pub xkb_variant: Option<String>,
+    /// The keyboard options, exported as XKB_DEFAULT_OPTIONS.
+    pub xkb_options: Option<String>,
+    /// The prefix of variables to remove from the session environment, as
+    /// they are internal to greetd and its greeters. Defaults to "GREETD_",
+    /// while an empty prefix keeps all variables.
+    pub env_scrub_prefix: Option<String>,
+    /// Variables to keep despite matching the prefix, in addition to
+    /// GREETD_SOCK. The session cookie is always kept.
+    pub env_scrub_keep: Vec<String>,
+    /// Variables to forward from the environment of greetd to the session.
+    /// These take the lowest precedence, so variables set by any other
+    /// means are not replaced.
+    pub forward_env: Vec<String>,
+    /// Refuse switches away from the VT of the session for this long after
+    /// it has been set up, so that the compositor can take over the display
+    /// undisturbed. This also keeps the user from switching away from a
+    /// session that hangs in the meantime, so zero, the default, disables it.
+    pub vt_switch_grace_ms: u64,
+    /// Make the user the owner of the terminal device with this mode, such
+    /// as 0o620, for the duration of the session. This is for systems where
+    /// logind does not grant access to the device, and must not be used on
+    /// ones where it does.
+    pub tty_owner_mode: Option<u32>,
+    /// Report how long each phase of the login took with a Timings message.
+    pub report_timings: bool,
+    /// Leave the terminal settings as they are, rather than resetting them
+    /// to sane defaults, for sessions that rely on settings made before.
+    pub keep_termios: bool,
+    /// A group to run the session with as its primary group instead of the
+    /// primary group of the user, such as a shared project group. The user
+    /// must be a member of the group, and keeps its other groups.
+    pub primary_group: Option<String>,
+}
+
+/// The PAM services that may be used for each session class, as pairs of
+/// class and services, such as ("greeter", ["greetd-greeter"]). If any are
+/// given, a login of a class without an entry is refused, so that a
+/// high-trust service cannot be used for the wrong class. The policy comes
+/// from the configuration, and is given to the worker when it is started
+/// rather than along with the service it is to check.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ServicePolicy(pub Vec<(String, Vec<String>)>);
+
+impl ServicePolicy {
+    /// Check that the service may be used for a login of the class.
+    pub fn check(&self, class: &str, service: &str) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let allowed = self
+            .0
+            .iter()
+            .filter(|(c, _)| c == class)
+            .any(|(_, services)| services.iter().any(|s| s == service));
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::ProtocolError(format!(
+                "service {} is not allowed for class {}",
+                service, class
+            )))
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ParentToSessionChild {
     InitiateLogin {
@@ -41,6 +320,19 @@ pub enum ParentToSessionChild {
         authenticate: bool,
         tty: TerminalMode,
         source_profile: bool,
+        options: Box<LoginOptions>,
+    },
+    /// Verify the credentials of the user against the service without
+    /// opening a session, such as to unlock a locked session.
+    Reauthenticate {
+        service: String,
+        user: String,
+    },
+    /// Change the authentication token, that is the password, of the user
+    /// with the service, prompting as PAM directs, without opening a session.
+    ChangeAuthtok {
+        service: String,
+        user: String,
     },
     PamResponse {
         resp: Option<String>,
@@ -49,39 +341,343 @@ pub enum ParentToSessionChild {
         cmd: Vec<String>,
     },
     Start,
-    Cancel,
+    /// Start the session in place of the greeter, which has exited. The
+    /// terminal set up for the greeter is taken over without being cleared,
+    /// though it is still switched to if needed. The PAM transaction of the greeter
+    /// cannot be taken over, as it is bound to the greeter user and service
+    /// and its session is registered as a greeter, so the session uses the
+    /// transaction in which its user was authenticated.
+    HandOff,
+    /// Cancel the login, optionally explaining why for the logs. Parents
+    /// that predate the reason send a bare "Cancel", which is accepted as a
+    /// cancel without a reason.
+    Cancel {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    PollMessages,
 }
 
 impl ParentToSessionChild {
     pub fn recv(sock: &UnixDatagram) -> Result<ParentToSessionChild, Error> {
-        let mut data = [0; 10240];
-        let len = sock.recv(&mut data[..])?;
-        let msg = serde_json::from_slice(&data[..len])?;
-        Ok(msg)
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = recv_retrying(|| sock.recv(&mut data[..]))?;
+        match serde_json::from_slice(&data[..len]) {
+            Ok(msg) => Ok(msg),
+            Err(e) => match serde_json::from_slice::<String>(&data[..len]) {
+                Ok(unit) if unit == "Cancel" => Ok(ParentToSessionChild::Cancel { reason: None }),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Receive a message outside of the PAM conversation. A polling parent
+    /// may race the end of the conversation with a PollMessages, which is
+    /// ignored as the parent receives the conversation result instead.
+    fn recv_skip_polls(sock: &UnixDatagram) -> Result<ParentToSessionChild, Error> {
+        loop {
+            match ParentToSessionChild::recv(sock)? {
+                ParentToSessionChild::PollMessages => continue,
+                msg => return Ok(msg),
+            }
+        }
+    }
+}
+
+/// Whether a socket error means that the peer has gone away.
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// How many times to retry a receive that failed transiently.
+const RECV_RETRIES: u32 = 5;
+/// The delay before retrying a receive that failed transiently.
+const RECV_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Whether a socket error is likely to go away if the receive is retried.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EAGAIN) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+    )
+}
+
+/// Receive a message, retrying a few times on transient errors. Interrupted
+/// receives are retried right away, and do not count as retries.
+fn recv_retrying<F>(mut recv: F) -> Result<usize, Error>
+where
+    F: FnMut() -> io::Result<usize>,
+{
+    let mut attempt = 0;
+    loop {
+        match recv() {
+            Ok(0) => return Err(Error::PeerDisconnected),
+            Ok(len) => return Ok(len),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if is_disconnect(&e) => return Err(Error::PeerDisconnected),
+            Err(e) if is_transient(&e) && attempt < RECV_RETRIES => {
+                attempt += 1;
+                thread::sleep(RECV_RETRY_DELAY);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Create a connected pair of sockets for talking to a session worker.
+/// Sequenced packets preserve message boundaries like datagrams, but unlike
+/// datagrams report the peer closing its end.
+pub fn socket_pair() -> Result<(UnixDatagram, UnixDatagram), Error> {
+    let (a, b) = socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )
+    .map_err(|e| format!("unable to create socket pair: {}", e))?;
+    Ok(unsafe { (UnixDatagram::from_raw_fd(a), UnixDatagram::from_raw_fd(b)) })
+}
+
+/// Set or clear the close-on-exec flag of a file descriptor. The control
+/// socket must only be inheritable by the worker it is passed to, and never
+/// by the session.
+pub fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<(), Error> {
+    let mut flags = unsafe { FdFlag::from_bits_unchecked(fcntl(fd, FcntlArg::F_GETFD)?) };
+    flags.set(FdFlag::FD_CLOEXEC, cloexec);
+    fcntl(fd, FcntlArg::F_SETFD(flags))?;
+    Ok(())
+}
+
+/// A PAM message queued for a polling parent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub style: AuthMessageType,
+    pub severity: Severity,
+    pub msg: String,
+}
+
+/// The version of the protocol spoken between greetd and its session workers.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The optional features supported by this session worker, depending on both
+/// the features it was built with and what the running system provides.
+pub fn capabilities() -> Vec<String> {
+    capabilities_at(
+        Path::new(LOGINUID_PATH),
+        Path::new(CGROUP_ROOT),
+        Path::new(UTMP_PATH),
+    )
+}
+
+/// The capabilities, probing the system facilities at the specified paths.
+fn capabilities_at(loginuid: &Path, cgroup_root: &Path, utmp: &Path) -> Vec<String> {
+    let mut caps = vec![
+        "session_cookie",
+        "poll_conversation",
+        "user_env",
+        "fallback_cmd",
+        "reauthenticate",
+        "change_authtok",
+        "auth_service_cached",
+        "hand_off",
+        "report_timings",
+        "primary_group",
+    ];
+    if loginuid.exists() {
+        caps.push("loginuid");
     }
+    if cgroup::supported(cgroup_root) {
+        caps.push("cgroup");
+    }
+    if utmp.exists() {
+        caps.push("utmp");
+    }
+    caps.iter().map(|c| c.to_string()).collect()
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SessionChildToParent {
+    Ready {
+        version: u32,
+        capabilities: Vec<String>,
+    },
     Success,
     Error(Error),
-    PamMessage { style: AuthMessageType, msg: String },
+    PamMessage {
+        style: AuthMessageType,
+        severity: Severity,
+        msg: String,
+    },
+    PendingMessages(Vec<PendingMessage>),
+    /// The user was authenticated, either by the cached service without
+    /// prompting, or by the service of the login. Only sent if a cached
+    /// service was requested. It is only logged, as greeters see a cached
+    /// authentication as one without prompts.
+    Authenticated {
+        cached: bool,
+    },
+    /// The session command could not be executed, and the fallback command
+    /// was started instead.
+    FallbackStarted {
+        cmd: Vec<String>,
+    },
+    /// How long each phase of the login took. Only sent if requested, before
+    /// SessionStarted.
+    Timings(Timings),
+    /// The session process was started, at the specified number of
+    /// milliseconds since the Unix epoch. Sent before FinalChildPid, and
+    /// kept with the session child.
+    SessionStarted {
+        pid: u64,
+        pgid: u64,
+        started_at: u64,
+    },
     FinalChildPid(u64),
 }
 
 impl SessionChildToParent {
     pub fn send(&self, sock: &UnixDatagram) -> Result<(), Error> {
-        let out = serde_json::to_vec(self)?;
-        sock.send(&out)?;
-        Ok(())
+        let out = encode(self)?;
+        check_sent(sock.send(&out), out.len())
+    }
+}
+
+/// The largest message that can be exchanged with a session worker, which is
+/// the size of the receive buffers.
+pub const MAX_MESSAGE_SIZE: usize = 10240;
+
+/// Serialize a message, ensuring that it fits in the receive buffer of the
+/// peer.
+pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>, Error> {
+    let out = serde_json::to_vec(msg)?;
+    if out.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::ProtocolError(format!(
+            "message of {} bytes exceeds limit of {} bytes",
+            out.len(),
+            MAX_MESSAGE_SIZE
+        )));
+    }
+    Ok(out)
+}
+
+/// Check the result of sending a message. Messages are sent as single
+/// packets, so anything short of the full message is an error.
+pub fn check_sent(res: io::Result<usize>, len: usize) -> Result<(), Error> {
+    match res {
+        Ok(sent) if sent == len => Ok(()),
+        Ok(sent) => Err(Error::Io(format!(
+            "short send of message: {} of {} bytes",
+            sent, len
+        ))),
+        Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => Err(Error::ProtocolError(format!(
+            "message of {} bytes too large for socket",
+            len
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Log a cancellation from our parent, and produce the error to tear down
+/// with.
+pub fn cancelled(reason: Option<String>) -> Error {
+    let msg = match reason {
+        Some(reason) => format!("cancelled: {}", reason),
+        None => "cancelled".to_string(),
+    };
+    eprintln!("session: {}", msg);
+    Error::Error(msg)
+}
+
+/// Describe the start of the session process, whose process group is that of
+/// the worker unless the session made one of its own.
+fn session_started(pid: Pid, started_at: SystemTime) -> SessionChildToParent {
+    let pgid = getpgid(Some(pid)).unwrap_or_else(|_| getpgrp());
+    let started_at = started_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    SessionChildToParent::SessionStarted {
+        pid: pid.as_raw() as u64,
+        pgid: pgid.as_raw() as u64,
+        started_at: started_at as u64,
+    }
+}
+
+/// Authenticate the user, and wait for the parent to provide the command and
+/// to request the start of the session.
+fn prepare_login(
+    login: &mut Login,
+    sock: &UnixDatagram,
+    queue: &PollQueue,
+    authenticate: bool,
+) -> Result<(), Error> {
+    if authenticate {
+        let path = login.authenticate()?;
+        if login.has_cached_service() {
+            SessionChildToParent::Authenticated {
+                cached: path == AuthPath::Cached,
+            }
+            .send(sock)?;
+        }
     }
+    login.authorize()?;
+
+    // Mark authentication as a success, after any messages still held for a
+    // polling parent.
+    queue.flush(sock)?;
+    SessionChildToParent::Success.send(sock)?;
+
+    // Fetch our arguments from the parent.
+    let cmd = match ParentToSessionChild::recv_skip_polls(sock)? {
+        ParentToSessionChild::Args { cmd } => cmd,
+        ParentToSessionChild::Cancel { reason } => return Err(cancelled(reason)),
+        msg => return Err(format!("expected Args or Cancel, got: {:?}", msg).into()),
+    };
+    login.set_args(cmd);
+
+    SessionChildToParent::Success.send(sock)?;
+
+    // Await start request from our parent.
+    match ParentToSessionChild::recv_skip_polls(sock)? {
+        ParentToSessionChild::Start => (),
+        ParentToSessionChild::HandOff => login.hand_off(),
+        ParentToSessionChild::Cancel { reason } => return Err(cancelled(reason)),
+        msg => return Err(format!("expected Start, HandOff or Cancel, got: {:?}", msg).into()),
+    };
+
+    Ok(())
+}
+
+/// Run a PAM operation that opens no session, such as reauthentication or a
+/// password change, through the conversation with the parent, and report
+/// success. Failures are reported by main like any other error.
+fn converse_only<F>(sock: &UnixDatagram, operation: F) -> Result<(), Error>
+where
+    F: FnOnce(Pin<Box<dyn Converse + '_>>) -> Result<(), Error>,
+{
+    operation(Box::pin(SessionConv::new(sock)))?;
+    SessionChildToParent::Success.send(sock)
 }
 
 /// The entry point for the session worker process. The session worker is
 /// responsible for the entirety of the session setup and execution. It is
-/// started by Session::start.
-fn worker(sock: &UnixDatagram) -> Result<(), Error> {
-    let (service, class, user, authenticate, tty, source_profile) =
+/// started by Session::start. Every PAM service the worker is asked to use
+/// is checked against the policy it was started with.
+fn worker(sock: &UnixDatagram, policy: &ServicePolicy) -> Result<(), Error> {
+    // Let our parent know that we are ready to receive InitiateLogin.
+    SessionChildToParent::Ready {
+        version: PROTOCOL_VERSION,
+        capabilities: capabilities(),
+    }
+    .send(sock)?;
+
+    let (service, class, user, authenticate, tty, source_profile, options) =
         match ParentToSessionChild::recv(sock)? {
             ParentToSessionChild::InitiateLogin {
                 service,
@@ -90,211 +686,874 @@ fn worker(sock: &UnixDatagram) -> Result<(), Error> {
                 authenticate,
                 tty,
                 source_profile,
-            } => (service, class, user, authenticate, tty, source_profile),
-            ParentToSessionChild::Cancel => return Err("cancelled".into()),
-            msg => return Err(format!("expected InitiateLogin or Cancel, got: {:?}", msg).into()),
+                options,
+            } => (
+                service,
+                class,
+                user,
+                authenticate,
+                tty,
+                source_profile,
+                options,
+            ),
+            ParentToSessionChild::Reauthenticate { service, user } => {
+                policy.check("user", &service)?;
+                return converse_only(sock, |conv| login::reauthenticate(&service, &user, conv));
+            }
+            ParentToSessionChild::ChangeAuthtok { service, user } => {
+                policy.check("user", &service)?;
+                return converse_only(sock, |conv| login::change_authtok(&service, &user, conv));
+            }
+            ParentToSessionChild::Cancel { reason } => return Err(cancelled(reason)),
+            msg => {
+                return Err(format!(
+                    "expected InitiateLogin, Reauthenticate, ChangeAuthtok or Cancel, got: {:?}",
+                    msg
+                )
+                .into())
+            }
         };
 
-    let conv = Box::pin(SessionConv::new(sock));
-    let mut pam = PamSession::start(&service, &user, conv)?;
+    policy.check(&class, &service)?;
+    tty.validate()?;
 
-    if authenticate {
-        pam.authenticate(PamFlag::NONE)?;
+    // Messages held for a polling parent, and the timings if requested, are
+    // delivered before any error, which is reported by main.
+    let report_timings = options.report_timings;
+    let queue = PollQueue::default();
+    let report = |e: Error, timings: &Timings| {
+        if let Err(e) = queue.flush(sock) {
+            eprintln!("session: unable to deliver pending messages: {}", e);
+        }
+        if report_timings {
+            if let Err(e) = SessionChildToParent::Timings(timings.clone()).send(sock) {
+                eprintln!("session: unable to report timings: {}", e);
+            }
+        }
+        e
+    };
+
+    let conv: Pin<Box<dyn Converse>> = if options.poll_conversation {
+        Box::pin(PollingConv::new(sock, &queue))
+    } else {
+        Box::pin(SessionConv::new(sock))
+    };
+    let mut login = Login::start(&service, &class, &user, conv, source_profile, *options)?;
+
+    // Lets the tests see how a real worker binary reports a panic.
+    if cfg!(debug_assertions) && std::env::var_os("GREETD_TEST_WORKER_PANIC").is_some() {
+        panic!("induced by GREETD_TEST_WORKER_PANIC");
+    }
+
+    // If the login is aborted before the session is opened, such as by a
+    // cancel or by the parent disconnecting, PAM is torn down before we go.
+    if let Err(e) = prepare_login(&mut login, sock, &queue, authenticate) {
+        let e = report(e, login.timings());
+        login.cancel();
+        return Err(e);
     }
-    pam.acct_mgmt(PamFlag::NONE)?;
 
-    // Not the credentials you think.
-    pam.setcred(PamFlag::ESTABLISH_CRED)?;
+    let session = login
+        .run_timed(tty)
+        .map_err(|(e, timings)| report(e, &timings))?;
+    queue.flush(sock)?;
+    if let Some(cmd) = session.fallback() {
+        SessionChildToParent::FallbackStarted { cmd: cmd.to_vec() }.send(sock)?;
+    }
 
-    // Mark authentication as a success.
-    SessionChildToParent::Success.send(sock)?;
+    if report_timings {
+        SessionChildToParent::Timings(session.timings().clone()).send(sock)?;
+    }
 
-    // Fetch our arguments from the parent.
-    let cmd = match ParentToSessionChild::recv(sock)? {
-        ParentToSessionChild::Args { cmd } => cmd,
-        ParentToSessionChild::Cancel => return Err("cancelled".into()),
-        msg => return Err(format!("expected Args or Cancel, got: {:?}", msg).into()),
-    };
+    // Signal the inner PID to the parent process.
+    session_started(session.pid(), session.started_at()).send(sock)?;
+    SessionChildToParent::FinalChildPid(session.pid().as_raw() as u64).send(sock)?;
+    sock.shutdown(std::net::Shutdown::Both)?;
 
-    SessionChildToParent::Success.send(sock)?;
+    // Set our parent death signal. setsid above resets the signal, hence our
+    // late assignment, which is why we do this here.
+    prctl(PrctlOption::SET_PDEATHSIG(libc::SIGTERM))?;
 
-    // Await start request from our parent.
-    match ParentToSessionChild::recv(sock)? {
-        ParentToSessionChild::Start => (),
-        ParentToSessionChild::Cancel => return Err("cancelled".into()),
-        msg => return Err(format!("expected Start or Cancel, got: {:?}", msg).into()),
+    session.wait()
+}
+
+/// Describe the payload of a panic.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown cause"
+    }
+}
+
+/// Run the worker, turning a panic into an error. Where panics unwind, the
+/// unwinding drops the login, which ends the PAM transaction, and the error is
+/// reported by main. Our builds use panic=abort, where nothing unwinds, so the
+/// panic hook reports the error and ends the PAM transactions itself before
+/// the worker exits. A forked child of the worker that panics exits at the
+/// panic instead, as unwinding would run the Drops of the worker state it
+/// shares, tearing down the login under the worker.
+fn run_guarded<F>(sock: &UnixDatagram, worker: F) -> Result<(), Error>
+where
+    F: FnOnce(&UnixDatagram) -> Result<(), Error>,
+{
+    let pid = getpid();
+    let fd = sock.as_raw_fd();
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        hook(info);
+        if getpid() != pid {
+            unsafe { libc::_exit(1) };
+        }
+        if cfg!(panic = "abort") {
+            // The socket remains owned by our caller.
+            let sock = ManuallyDrop::new(unsafe { UnixDatagram::from_raw_fd(fd) });
+            let e = Error::Error(format!(
+                "session worker panicked: {}",
+                panic_message(info.payload())
+            ));
+            if let Err(e) = SessionChildToParent::Error(e).send(&sock) {
+                eprintln!("session: unable to report panic: {}", e);
+            }
+            end_abandoned();
+            unsafe { libc::_exit(1) };
+        }
+    }));
+    let res = panic::catch_unwind(AssertUnwindSafe(|| worker(sock)));
+    if getpid() != pid {
+        // A forked child of the worker must never get back to greetd.
+        unsafe { libc::_exit(1) };
+    }
+    match res {
+        Ok(res) => res,
+        Err(payload) => Err(Error::Error(format!(
+            "session worker panicked: {}",
+            panic_message(&*payload)
+        ))),
+    }
+}
+
+pub fn main(sock: &UnixDatagram, policy: &ServicePolicy) -> Result<(), Error> {
+    main_with(sock, |sock| worker(sock, policy))
+}
+
+fn main_with<F>(sock: &UnixDatagram, worker: F) -> Result<(), Error>
+where
+    F: FnOnce(&UnixDatagram) -> Result<(), Error>,
+{
+    match run_guarded(sock, worker) {
+        // There is nobody left to tell, so this is an implicit cancel.
+        Err(Error::PeerDisconnected) => {
+            eprintln!("session: cancelled: peer disconnected");
+            Err(Error::PeerDisconnected)
+        }
+        Err(e) => {
+            SessionChildToParent::Error(e.clone()).send(sock)?;
+            Err(e)
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
     };
+    use std::{ffi::CStr, os::unix::io::AsRawFd, process::Command};
+
+    fn child_fds() -> Vec<RawFd> {
+        let out = Command::new("/bin/ls")
+            .arg("/proc/self/fd")
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout)
+            .unwrap()
+            .lines()
+            .map(|fd| fd.parse().unwrap())
+            .collect()
+    }
 
-    let pam_username = pam.get_user()?;
+    #[test]
+    fn control_socket_cloexec() {
+        let (_parent, child) = socket_pair().unwrap();
+        let fd = child.as_raw_fd();
+        assert!(!child_fds().contains(&fd));
 
-    let user = users::get_user_by_name(&pam_username).ok_or("unable to get user info")?;
+        set_cloexec(fd, false).unwrap();
+        assert!(child_fds().contains(&fd));
 
-    // Make this process a session leader.
-    setsid().map_err(|e| format!("unable to become session leader: {}", e))?;
+        set_cloexec(fd, true).unwrap();
+        assert!(!child_fds().contains(&fd));
+    }
+
+    #[test]
+    fn vt_range() {
+        let term = |vt| TerminalMode::Terminal {
+            path: format!("/dev/tty{}", vt),
+            vt,
+            switch: true,
+        };
+        assert!(term(1).validate().is_ok());
+        assert!(term(7).validate().is_ok());
+        assert!(term(MAX_VT).validate().is_ok());
+        assert!(TerminalMode::Stdin.validate().is_ok());
+
+        for &vt in &[0, MAX_VT + 1, usize::MAX] {
+            assert!(
+                matches!(term(vt).validate(), Err(Error::ProtocolError(_))),
+                "{}",
+                vt
+            );
+        }
+    }
 
-    match tty {
-        TerminalMode::Stdin => (),
-        TerminalMode::Terminal { path, vt, switch } => {
-            // Tell PAM what TTY we're targetting, which is used by logind.
-            pam.set_item(PamItemType::TTY, &format!("tty{}", vt))?;
-            pam.putenv(&format!("XDG_VTNR={}", vt))?;
+    #[test]
+    fn message_severity() {
+        for (style, severity) in &[
+            (AuthMessageType::Visible, Severity::Notice),
+            (AuthMessageType::Secret, Severity::Notice),
+            (AuthMessageType::Info, Severity::Info),
+            (AuthMessageType::Error, Severity::Error),
+        ] {
+            assert_eq!(style.severity(), *severity, "{:?}", style);
+        }
+        assert_eq!(
+            serde_json::to_string(&Severity::Notice).unwrap(),
+            "\"notice\""
+        );
+    }
 
-            // Opening our target terminal.
-            let target_term = terminal::Terminal::open(&path)?;
+    #[test]
+    fn session_cookie() {
+        let cookie = SessionCookie("hunter2".to_string());
+        assert!(!format!("{:?}", cookie).contains("hunter2"));
 
-            // Set the target VT mode to text for compatibility. Other login managers
-            // set this to graphics, but that disallows start of textual applications,
-            // which greetd aims to support.
-            target_term.kd_setmode(terminal::KdMode::Text)?;
+        let mut env = PamEnv::default();
+        env.set("HOME", "/home/john").unwrap();
+        env.set("GREETD_SESSION_COOKIE", "stale").unwrap();
+        cookie.apply(&mut env).unwrap();
+        assert_eq!(
+            env.to_vec(),
+            vec![
+                CStr::from_bytes_with_nul(b"HOME=/home/john\0").unwrap(),
+                CStr::from_bytes_with_nul(b"GREETD_SESSION_COOKIE=hunter2\0").unwrap()
+            ]
+        );
+    }
 
-            // Clear TTY so that it will be empty when we switch to it.
-            target_term.term_clear()?;
+    #[test]
+    fn ready_handshake() {
+        let (worker_sock, parent) = UnixDatagram::pair().unwrap();
+        let worker = thread::spawn(move || main(&worker_sock, &ServicePolicy::default()));
 
-            // A bit more work if a VT switch is required.
-            if switch && vt != target_term.vt_get_current()? {
-                // Perform a switch to the target VT, simultaneously resetting it to
-                // VT_AUTO.
-                target_term.vt_setactivate(vt)?;
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = parent.recv(&mut data[..]).unwrap();
+        match serde_json::from_slice(&data[..len]).unwrap() {
+            SessionChildToParent::Ready {
+                version,
+                capabilities,
+            } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert!(capabilities.contains(&"poll_conversation".to_string()));
             }
+            msg => panic!("expected Ready, got: {:?}", msg),
+        }
 
-            // Connect std(in|out|err), and make this our controlling TTY.
-            target_term.term_connect_pipes()?;
-            target_term.term_take_ctty()?;
+        let cancel = ParentToSessionChild::Cancel { reason: None };
+        parent.send(&serde_json::to_vec(&cancel).unwrap()).unwrap();
+        assert!(worker.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn bare_cancel() {
+        let (worker_sock, parent) = socket_pair().unwrap();
+        for msg in &[
+            &br#""Cancel""#[..],
+            &br#"{"Cancel":{}}"#[..],
+            &br#"{"Cancel":{"reason":null}}"#[..],
+        ] {
+            parent.send(msg).unwrap();
+            assert!(matches!(
+                ParentToSessionChild::recv(&worker_sock).unwrap(),
+                ParentToSessionChild::Cancel { reason: None }
+            ));
         }
+        parent.send(br#""Start""#).unwrap();
+        assert!(matches!(
+            ParentToSessionChild::recv(&worker_sock).unwrap(),
+            ParentToSessionChild::Start
+        ));
+        parent.send(br#""Cancelled""#).unwrap();
+        assert!(ParentToSessionChild::recv(&worker_sock).is_err());
     }
 
-    // Prepare some values from the user struct we gathered earlier.
-    let username = user.name().to_str().unwrap_or("");
-    let home = user.home_dir().to_str().unwrap_or("");
-    let shell = user.shell().to_str().unwrap_or("");
-    let uid = Uid::from_raw(user.uid());
-    let gid = Gid::from_raw(user.primary_group_id());
+    #[test]
+    fn runtime_capabilities() {
+        let root = std::env::temp_dir().join(format!("greetd-caps-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir(&root).unwrap();
+        let (loginuid, utmp) = (root.join("loginuid"), root.join("utmp"));
+        let probed = || capabilities_at(&loginuid, &root, &utmp);
 
-    // Change working directory
-    let pwd = match env::set_current_dir(home) {
-        Ok(_) => home,
-        Err(_) => {
-            env::set_current_dir("/")
-                .map_err(|e| format!("unable to set working directory: {}", e))?;
-            "/"
+        let base = probed();
+        assert!(base.contains(&"poll_conversation".to_string()));
+        for cap in &["loginuid", "cgroup", "utmp"] {
+            assert!(!base.contains(&cap.to_string()), "{}", cap);
         }
-    };
 
-    // PAM has to be provided a bunch of environment variables before
-    // open_session. We pass any environment variables from our greeter
-    // through here as well. This allows them to affect PAM (more
-    // specifically, pam_systemd.so), as well as make it easier to gather
-    // and set all environment variables later.
-    let prepared_env = [
-        "XDG_SEAT=seat0".to_string(),
-        format!("XDG_SESSION_CLASS={}", class),
-        format!("USER={}", username),
-        format!("LOGNAME={}", username),
-        format!("HOME={}", home),
-        format!("SHELL={}", shell),
-        format!("PWD={}", pwd),
-        format!("GREETD_SOCK={}", env::var("GREETD_SOCK").unwrap()),
-        format!(
-            "TERM={}",
-            env::var("TERM").unwrap_or_else(|_| "linux".to_string())
-        ),
-    ];
+        // Each capability is probed from its own file, and flips with it
+        // alone. cgroup support also needs the cgroup feature.
+        for (file, cap, supported) in &[
+            (loginuid.clone(), "loginuid", true),
+            (
+                root.join("cgroup.controllers"),
+                "cgroup",
+                cfg!(feature = "cgroup"),
+            ),
+            (utmp.clone(), "utmp", true),
+        ] {
+            std::fs::write(file, "").unwrap();
+            let added: Vec<String> = probed().into_iter().filter(|c| !base.contains(c)).collect();
+            let expected: Vec<&str> = if *supported { vec![cap] } else { vec![] };
+            assert_eq!(added, expected);
 
-    for e in prepared_env.iter() {
-        pam.putenv(e)?;
+            std::fs::remove_file(file).unwrap();
+            assert_eq!(probed(), base);
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    // Session time!
-    pam.open_session(PamFlag::NONE)?;
+    #[test]
+    fn peer_disconnect() {
+        let (worker_sock, parent) = socket_pair().unwrap();
+        let worker = thread::spawn(move || main(&worker_sock, &ServicePolicy::default()));
 
-    // Prepare some strings in C format that we'll need.
-    let cusername = CString::new(username)?;
-    let command = if source_profile {
-        format!(
-            "[ -f /etc/profile ] && . /etc/profile; [ -f $HOME/.profile ] && . $HOME/.profile; exec {}",
-            cmd.join(" ")
-        )
-    } else {
-        format!("exec {}", cmd.join(" "))
-    };
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = parent.recv(&mut data[..]).unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&data[..len]).unwrap(),
+            SessionChildToParent::Ready { .. }
+        ));
 
-    // Extract PAM environment for use with execve below.
-    let pamenvlist = pam.getenvlist()?;
-    let envvec = pamenvlist.to_vec();
-
-    // PAM is weird and gets upset if you exec from the process that opened
-    // the session, registering it automatically as a log-out. Thus, we must
-    // exec in a new child.
-    let child = match fork().map_err(|e| format!("unable to fork: {}", e))? {
-        ForkResult::Parent { child, .. } => child,
-        ForkResult::Child => {
-            // It is important that we do *not* return from here by
-            // accidentally using '?'. The process *must* exit from within
-            // this match arm.
-
-            // Drop privileges to target user
-            initgroups(&cusername, gid).expect("unable to init groups");
-            setgid(gid).expect("unable to set GID");
-            setuid(uid).expect("unable to set UID");
-
-            // Set our parent death signal. setuid/setgid above resets the
-            // death signal, which is why we do this here.
-            prctl(PrctlOption::SET_PDEATHSIG(libc::SIGTERM)).expect("unable to set death signal");
-
-            // Run
-            let cpath = CString::new("/bin/sh").unwrap();
-            execve(
-                &cpath,
-                &[
-                    &cpath,
-                    &CString::new("-c").unwrap(),
-                    &CString::new(command).unwrap(),
-                ],
-                &envvec,
-            )
-            .expect("unable to exec");
-
-            unreachable!("after exec");
-        }
-    };
+        drop(parent);
+        assert!(matches!(
+            worker.join().unwrap(),
+            Err(Error::PeerDisconnected)
+        ));
+    }
 
-    // Signal the inner PID to the parent process.
-    SessionChildToParent::FinalChildPid(child.as_raw() as u64).send(sock)?;
-    sock.shutdown(std::net::Shutdown::Both)?;
+    /// Receive through a sequence of injected results.
+    fn recv_results(results: Vec<io::Result<usize>>) -> (Result<usize, Error>, usize) {
+        let mut results = results.into_iter();
+        let mut calls = 0;
+        let res = recv_retrying(|| {
+            calls += 1;
+            results.next().unwrap()
+        });
+        (res, calls)
+    }
 
-    // Set our parent death signal. setsid above resets the signal, hence our
-    // late assignment, which is why we do this here.
-    prctl(PrctlOption::SET_PDEATHSIG(libc::SIGTERM))?;
+    #[test]
+    fn recv_retry() {
+        let err = io::Error::from_raw_os_error;
 
-    // Wait for process to terminate, handling EINTR as necessary.
-    loop {
-        match waitpid(child, None) {
-            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
-            Err(e) => {
-                eprintln!("session: waitpid on inner child failed: {}", e);
-                break;
+        let (res, calls) = recv_results(vec![Err(err(libc::ENOBUFS)), Ok(10)]);
+        assert_eq!(res.unwrap(), 10);
+        assert_eq!(calls, 2);
+
+        let (res, calls) = recv_results(vec![
+            Err(err(libc::EAGAIN)),
+            Err(err(libc::EINTR)),
+            Err(err(libc::ENOMEM)),
+            Ok(10),
+        ]);
+        assert_eq!(res.unwrap(), 10);
+        assert_eq!(calls, 4);
+
+        // Retries are bounded, but interruptions do not count.
+        let mut results: Vec<_> = (0..=RECV_RETRIES).map(|_| Err(err(libc::EAGAIN))).collect();
+        results.push(Ok(10));
+        let (res, calls) = recv_results(results);
+        assert!(matches!(res, Err(Error::Io(_))));
+        assert_eq!(calls, RECV_RETRIES as usize + 1);
+
+        let mut results: Vec<_> = (0..10).map(|_| Err(err(libc::EINTR))).collect();
+        results.push(Ok(10));
+        assert_eq!(recv_results(results).0.unwrap(), 10);
+
+        // Fatal errors fail right away.
+        let (res, calls) = recv_results(vec![Err(err(libc::ECONNRESET)), Ok(10)]);
+        assert!(matches!(res, Err(Error::PeerDisconnected)));
+        assert_eq!(calls, 1);
+        let (res, calls) = recv_results(vec![Ok(0), Ok(10)]);
+        assert!(matches!(res, Err(Error::PeerDisconnected)));
+        assert_eq!(calls, 1);
+        let (res, calls) = recv_results(vec![Err(err(libc::EBADF)), Ok(10)]);
+        assert!(matches!(res, Err(Error::Io(_))));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn max_message_size() {
+        let (worker_sock, parent) = socket_pair().unwrap();
+        let message = |len| SessionChildToParent::PamMessage {
+            style: AuthMessageType::Info,
+            severity: Severity::Info,
+            msg: "x".repeat(len),
+        };
+        let overhead = encode(&message(0)).unwrap().len();
+
+        let msg = message(MAX_MESSAGE_SIZE - overhead);
+        msg.send(&worker_sock).unwrap();
+        let mut data = [0; MAX_MESSAGE_SIZE + 1];
+        let len = parent.recv(&mut data[..]).unwrap();
+        assert_eq!(len, MAX_MESSAGE_SIZE);
+        match serde_json::from_slice(&data[..len]).unwrap() {
+            SessionChildToParent::PamMessage { msg, .. } => {
+                assert_eq!(msg, "x".repeat(MAX_MESSAGE_SIZE - overhead))
             }
-            Ok(_) => break,
+            msg => panic!("expected PamMessage, got: {:?}", msg),
+        }
+
+        let msg = message(MAX_MESSAGE_SIZE - overhead + 1);
+        assert!(matches!(
+            msg.send(&worker_sock),
+            Err(Error::ProtocolError(_))
+        ));
+
+        assert!(check_sent(Ok(10), 10).is_ok());
+        assert!(matches!(check_sent(Ok(5), 10), Err(Error::Io(_))));
+        assert!(matches!(
+            check_sent(Err(io::Error::from_raw_os_error(libc::EMSGSIZE)), 10),
+            Err(Error::ProtocolError(_))
+        ));
+    }
+
+    /// Answer the prompts of a worker in order until it reports its result,
+    /// and return whether it succeeded along with the messages it sent.
+    /// Prompts beyond the answers are declined.
+    fn answer_prompts(parent: &UnixDatagram, answers: &[&str]) -> (bool, Vec<String>) {
+        let mut answers = answers.iter();
+        let mut msgs = Vec::new();
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        loop {
+            let len = parent.recv(&mut data[..]).unwrap();
+            let resp = match serde_json::from_slice(&data[..len]).unwrap() {
+                SessionChildToParent::PamMessage { style, msg, .. } => {
+                    msgs.push(format!("{:?}: {}", style, msg));
+                    match style {
+                        AuthMessageType::Visible | AuthMessageType::Secret => {
+                            answers.next().map(|a| a.to_string())
+                        }
+                        AuthMessageType::Info | AuthMessageType::Error => None,
+                    }
+                }
+                SessionChildToParent::Success => return (true, msgs),
+                SessionChildToParent::Error(_) => return (false, msgs),
+                msg => panic!("expected PamMessage, Success or Error, got: {:?}", msg),
+            };
+            let resp = ParentToSessionChild::PamResponse { resp };
+            parent.send(&serde_json::to_vec(&resp).unwrap()).unwrap();
         }
     }
 
-    // Close the session. This step requires root privileges to run, as it
-    // will result in various forms of login teardown (including unmounting
-    // home folders, telling logind that the session ended, etc.). This is
-    // why we cannot drop privileges in this process, but must do it in the
-    // inner-most child.
-    pam.close_session(PamFlag::NONE)?;
-    pam.setcred(PamFlag::DELETE_CRED)?;
-    pam.end()?;
+    /// Run a PAM operation that opens no session in a worker, answering its
+    /// prompts in order, and return its result along with the messages it
+    /// sent.
+    fn converse_with<F>(operation: F, answers: &[&str]) -> (Result<(), Error>, Vec<String>)
+    where
+        F: FnOnce(Pin<Box<dyn Converse + '_>>) -> Result<(), Error> + Send + 'static,
+    {
+        let (worker_sock, parent) = socket_pair().unwrap();
+        let worker =
+            thread::spawn(move || main_with(&worker_sock, |sock| converse_only(sock, operation)));
+        let (_, msgs) = answer_prompts(&parent, answers);
+        (worker.join().unwrap(), msgs)
+    }
 
-    Ok(())
-}
+    /// Ask a worker to reauthenticate the user with the service, as greetd
+    /// does, answering its prompts in order. The result reported to the
+    /// parent is returned along with the messages the worker sent.
+    fn reauthenticate_with(service: &str, user: &str, answers: &[&str]) -> (bool, Vec<String>) {
+        let (worker_sock, parent) = socket_pair().unwrap();
+        let worker = thread::spawn(move || main(&worker_sock, &ServicePolicy::default()));
 
-pub fn main(sock: &UnixDatagram) -> Result<(), Error> {
-    if let Err(e) = worker(sock) {
-        SessionChildToParent::Error(e.clone()).send(sock)?;
-        Err(e)
-    } else {
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = parent.recv(&mut data[..]).unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&data[..len]).unwrap(),
+            SessionChildToParent::Ready { .. }
+        ));
+        let msg = ParentToSessionChild::Reauthenticate {
+            service: service.to_string(),
+            user: user.to_string(),
+        };
+        parent.send(&serde_json::to_vec(&msg).unwrap()).unwrap();
+
+        let res = answer_prompts(&parent, answers);
+        assert_eq!(worker.join().unwrap().is_ok(), res.0);
+        res
+    }
+
+    #[test]
+    fn reauthenticate_refused() {
+        // Whatever the host makes of an unknown service, a user without a
+        // password whose prompts are declined is not authenticated.
+        let (ok, _) = reauthenticate_with("greetd-test", "nobody", &[]);
+        assert!(!ok);
+    }
+
+    /// The reauthentication fixture service, found in tests/pam.d of this
+    /// crate, and the user it authenticates.
+    const REAUTH_SERVICE: &str = "greetd-reauthenticate";
+    const REAUTH_USER: &str = "greetd-reauthenticate";
+    const REAUTH_PASSWORD: &str = "greetd-Reauth-1";
+
+    /// Run with `sudo cargo test -- --ignored pam_reauthenticate`, after
+    /// installing tests/pam.d/greetd-reauthenticate in /etc/pam.d and
+    /// creating the user with `useradd greetd-reauthenticate` and
+    /// `echo greetd-reauthenticate:greetd-Reauth-1 | chpasswd`.
+    #[test]
+    #[ignore]
+    fn pam_reauthenticate() {
+        let (ok, msgs) = reauthenticate_with(REAUTH_SERVICE, REAUTH_USER, &[REAUTH_PASSWORD]);
+        assert!(ok, "{:?}", msgs);
+        // The password was asked for, and the account and session stacks of
+        // the fixture, which refuse everyone, were not run.
+        assert_eq!(
+            msgs.iter().filter(|m| m.starts_with("Secret: ")).count(),
+            1,
+            "{:?}",
+            msgs
+        );
+
+        let (ok, _) = reauthenticate_with(REAUTH_SERVICE, REAUTH_USER, &["greetd-Reauth-2"]);
+        assert!(!ok);
+        let (ok, _) = reauthenticate_with(
+            REAUTH_SERVICE,
+            "greetd-reauthenticate-nobody",
+            &[REAUTH_PASSWORD],
+        );
+        assert!(!ok);
+    }
+
+    fn change_password(conv: Pin<Box<dyn Converse + '_>>) -> Result<(), Error> {
+        let err = || Error::AuthError("authentication token manipulation error".to_string());
+        if conv.prompt_blind("Current password:").map_err(|_| err())? != "hunter2" {
+            return Err(err());
+        }
+        let new = conv.prompt_blind("New password:").map_err(|_| err())?;
+        if conv
+            .prompt_blind("Retype new password:")
+            .map_err(|_| err())?
+            != new
+        {
+            return Err(err());
+        }
         Ok(())
     }
+
+    #[test]
+    fn change_authtok() {
+        let (res, prompts) = converse_with(change_password, &["hunter2", "hunter3", "hunter3"]);
+        assert!(res.is_ok());
+        assert_eq!(
+            prompts,
+            vec![
+                "Secret: Current password:",
+                "Secret: New password:",
+                "Secret: Retype new password:"
+            ]
+        );
+
+        let (res, _) = converse_with(change_password, &["hunter2", "hunter3", "hunter4"]);
+        assert!(matches!(res, Err(Error::AuthError(_))));
+        let (res, prompts) = converse_with(change_password, &["hunter1"]);
+        assert!(matches!(res, Err(Error::AuthError(_))));
+        assert_eq!(prompts, vec!["Secret: Current password:"]);
+    }
+
+    /// The chauthtok fixture service, found in tests/pam.d of this crate,
+    /// and the user whose password it changes.
+    const CHAUTHTOK_SERVICE: &str = "greetd-chauthtok";
+    const CHAUTHTOK_USER: &str = "greetd-chauthtok";
+
+    /// Run with `sudo cargo test -- --ignored pam_change_authtok`, after
+    /// installing tests/pam.d/greetd-chauthtok in /etc/pam.d and creating
+    /// the user with `useradd greetd-chauthtok`. The password of the user
+    /// is overwritten.
+    #[test]
+    #[ignore]
+    fn pam_change_authtok() {
+        let change = |conv: Pin<Box<dyn Converse + '_>>| {
+            login::change_authtok(CHAUTHTOK_SERVICE, CHAUTHTOK_USER, conv)
+        };
+        let reauthenticate = |conv: Pin<Box<dyn Converse + '_>>| {
+            login::reauthenticate(CHAUTHTOK_SERVICE, CHAUTHTOK_USER, conv)
+        };
+
+        // Root is not asked for the current password.
+        let (res, msgs) = converse_with(change, &["greetd-Test-1", "greetd-Test-1"]);
+        res.unwrap();
+        let prompts: Vec<_> = msgs.iter().filter(|m| m.starts_with("Secret: ")).collect();
+        assert_eq!(prompts.len(), 2, "{:?}", msgs);
+        assert!(converse_with(reauthenticate, &["greetd-Test-1"]).0.is_ok());
+
+        let (res, _) = converse_with(change, &["greetd-Test-2", "greetd-Test-3"]);
+        assert!(res.is_err());
+        assert!(converse_with(reauthenticate, &["greetd-Test-2"]).0.is_err());
+        assert!(converse_with(reauthenticate, &["greetd-Test-1"]).0.is_ok());
+    }
+
+    #[test]
+    fn session_started_message() {
+        let before = SystemTime::now();
+        let child = match fork().unwrap() {
+            ForkResult::Parent { child } => child,
+            ForkResult::Child => {
+                thread::sleep(Duration::from_millis(100));
+                unsafe { libc::_exit(0) };
+            }
+        };
+        let started_at = SystemTime::now();
+
+        let (worker_sock, parent) = socket_pair().unwrap();
+        session_started(child, started_at)
+            .send(&worker_sock)
+            .unwrap();
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = parent.recv(&mut data[..]).unwrap();
+        match serde_json::from_slice(&data[..len]).unwrap() {
+            SessionChildToParent::SessionStarted {
+                pid,
+                pgid,
+                started_at,
+            } => {
+                assert_eq!(pid, child.as_raw() as u64);
+                assert_eq!(pgid, getpgrp().as_raw() as u64);
+                let before = before.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+                let after = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64;
+                assert!(before <= started_at && started_at <= after);
+            }
+            msg => panic!("expected SessionStarted, got: {:?}", msg),
+        }
+        waitpid(child, None).unwrap();
+    }
+
+    #[test]
+    fn timings_message() {
+        let phases = [
+            "authenticate",
+            "acct_mgmt",
+            "setcred",
+            "open_session",
+            "terminal",
+            "fork",
+        ];
+        let mut timings = Timings::default();
+        for phase in &phases {
+            let start = Instant::now();
+            thread::sleep(Duration::from_millis(1));
+            timings.record(phase, start);
+        }
+
+        let (worker_sock, parent) = socket_pair().unwrap();
+        SessionChildToParent::Timings(timings.clone())
+            .send(&worker_sock)
+            .unwrap();
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = parent.recv(&mut data[..]).unwrap();
+        match serde_json::from_slice(&data[..len]).unwrap() {
+            SessionChildToParent::Timings(received) => {
+                assert_eq!(received, timings);
+                let received: Vec<&str> = received.0.iter().map(|t| t.phase.as_str()).collect();
+                assert_eq!(received, phases);
+                assert!(timings.0.iter().all(|t| t.micros >= 1000));
+            }
+            msg => panic!("expected Timings, got: {:?}", msg),
+        }
+        assert!(timings.to_string().starts_with("authenticate: "));
+    }
+
+    #[test]
+    fn forked_panic() {
+        let (worker_sock, _parent) = UnixDatagram::pair().unwrap();
+        let (teardown_read, teardown_write) = UnixDatagram::pair().unwrap();
+
+        /// Reports being dropped, as worker state with a teardown would.
+        struct Teardown<'a>(&'a UnixDatagram);
+        impl Drop for Teardown<'_> {
+            fn drop(&mut self) {
+                let _ = self.0.send(getpid().to_string().as_bytes());
+            }
+        }
+
+        let res = run_guarded(&worker_sock, |_| {
+            let _teardown = Teardown(&teardown_write);
+            match fork().unwrap() {
+                ForkResult::Child => panic!("induced in child"),
+                ForkResult::Parent { child } => {
+                    assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 1));
+                }
+            }
+            Ok(())
+        });
+        assert!(res.is_ok());
+
+        // Only the worker itself tore down.
+        teardown_read.set_nonblocking(true).unwrap();
+        let mut data = [0; 16];
+        let len = teardown_read.recv(&mut data[..]).unwrap();
+        assert_eq!(&data[..len], getpid().to_string().as_bytes());
+        assert!(teardown_read.recv(&mut data[..]).is_err());
+    }
+
+    #[test]
+    fn worker_panic() {
+        let (worker_sock, parent) = UnixDatagram::pair().unwrap();
+        let worker = thread::spawn(move || {
+            main_with(&worker_sock, |sock| {
+                SessionChildToParent::Success.send(sock)?;
+                ParentToSessionChild::recv(sock)?;
+                panic!("induced");
+            })
+        });
+
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        let len = parent.recv(&mut data[..]).unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&data[..len]).unwrap(),
+            SessionChildToParent::Success
+        ));
+        parent
+            .send(&serde_json::to_vec(&ParentToSessionChild::Start).unwrap())
+            .unwrap();
+
+        let err = worker.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "session worker panicked: induced");
+        let len = parent.recv(&mut data[..]).unwrap();
+        match serde_json::from_slice(&data[..len]).unwrap() {
+            SessionChildToParent::Error(e) => assert_eq!(e.to_string(), err.to_string()),
+            msg => panic!("expected Error, got: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn cancel_reason() {
+        let (worker_sock, parent) = UnixDatagram::pair().unwrap();
+        let worker = thread::spawn(move || main(&worker_sock, &ServicePolicy::default()));
+
+        let cancel = ParentToSessionChild::Cancel {
+            reason: Some("user pressed escape".to_string()),
+        };
+        parent.send(&serde_json::to_vec(&cancel).unwrap()).unwrap();
+        let err = worker.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "cancelled: user pressed escape");
+
+        // Skip the Ready message to get to the teardown error.
+        let mut data = [0; MAX_MESSAGE_SIZE];
+        parent.recv(&mut data[..]).unwrap();
+        let len = parent.recv(&mut data[..]).unwrap();
+        match serde_json::from_slice(&data[..len]).unwrap() {
+            SessionChildToParent::Error(e) => {
+                assert_eq!(e.to_string(), "cancelled: user pressed escape")
+            }
+            msg => panic!("expected Error, got: {:?}", msg),
+        }
+    }
+
+    fn service_policy() -> ServicePolicy {
+        ServicePolicy(vec![
+            ("greeter".to_string(), vec!["greetd-greeter".to_string()]),
+            (
+                "user".to_string(),
+                vec!["greetd".to_string(), "login".to_string()],
+            ),
+        ])
+    }
+
+    #[test]
+    fn class_services() {
+        let policy = service_policy();
+        policy.check("greeter", "greetd-greeter").unwrap();
+        policy.check("user", "greetd").unwrap();
+        policy.check("user", "login").unwrap();
+        for (class, service) in &[
+            ("greeter", "greetd"),
+            ("greeter", "login"),
+            ("user", "greetd-greeter"),
+            ("background", "greetd"),
+        ] {
+            assert!(
+                matches!(policy.check(class, service), Err(Error::ProtocolError(_))),
+                "{} {}",
+                class,
+                service
+            );
+        }
+
+        // Without a policy, any service goes.
+        ServicePolicy::default().check("greeter", "greetd").unwrap();
+    }
+
+    /// Send a raw message to a worker started with the policy, and return
+    /// the result of the worker.
+    fn worker_with_policy(msg: serde_json::Value) -> Result<(), Error> {
+        let (worker_sock, parent) = UnixDatagram::pair().unwrap();
+        let worker = thread::spawn(move || main(&worker_sock, &service_policy()));
+        parent.send(&serde_json::to_vec(&msg).unwrap()).unwrap();
+        worker.join().unwrap()
+    }
+
+    #[test]
+    fn class_service_refused() {
+        // A login carrying a permissive policy of its own is still refused.
+        let mut options = serde_json::to_value(LoginOptions::default()).unwrap();
+        options["class_services"] = serde_json::json!([["greeter", ["greetd"]]]);
+        let login = serde_json::json!({
+            "InitiateLogin": {
+                "service": "greetd",
+                "class": "greeter",
+                "user": "root",
+                "authenticate": false,
+                "tty": "Stdin",
+                "source_profile": false,
+                "options": options,
+            }
+        });
+        assert!(matches!(
+            worker_with_policy(login),
+            Err(Error::ProtocolError(_))
+        ));
+
+        // Requests without a session are held to the policy of users.
+        for msg in &[
+            ParentToSessionChild::Reauthenticate {
+                service: "greetd-greeter".to_string(),
+                user: "root".to_string(),
+            },
+            ParentToSessionChild::ChangeAuthtok {
+                service: "other".to_string(),
+                user: "root".to_string(),
+            },
+        ] {
+            assert!(
+                matches!(
+                    worker_with_policy(serde_json::to_value(msg).unwrap()),
+                    Err(Error::ProtocolError(_))
+                ),
+                "{:?}",
+                msg
+            );
+        }
+    }
 }
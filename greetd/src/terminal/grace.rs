@@ -0,0 +1,229 @@
+use std::{
+    os::unix::io::RawFd,
+    sync::atomic::{AtomicI32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use super::{ioctl, Terminal, VtMode};
+use crate::error::Error;
+
+/// The signals the kernel sends us to request the release of the VT, and to
+/// announce its acquisition.
+const RELSIG: Signal = Signal::SIGUSR1;
+const ACQSIG: Signal = Signal::SIGUSR2;
+
+/// Stored in the otherwise unused frsig field, so that we can tell our own
+/// mode apart from that of a session which set up the same signals.
+const MARKER: u16 = 0x6764;
+
+/// The VT that the signal handlers answer for, or -1.
+static GRACE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn refuse_release(_: libc::c_int) {
+    let fd = GRACE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let _ = unsafe { ioctl::vt_reldisp(fd, 0) };
+    }
+}
+
+extern "C" fn acknowledge_acquire(_: libc::c_int) {
+    let fd = GRACE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let _ = unsafe { ioctl::vt_reldisp(fd, ioctl::VT_ACKACQ) };
+    }
+}
+
+/// The operations needed to hold a VT in process mode.
+pub trait VtModeOps {
+    fn fd(&self) -> RawFd;
+    fn vt_getmode(&self) -> Result<VtMode, Error>;
+    fn vt_setmode(&self, mode: VtMode) -> Result<(), Error>;
+}
+
+impl VtModeOps for Terminal {
+    fn fd(&self) -> RawFd {
+        self.fd
+    }
+    fn vt_getmode(&self) -> Result<VtMode, Error> {
+        Terminal::vt_getmode(self)
+    }
+    fn vt_setmode(&self, mode: VtMode) -> Result<(), Error> {
+        Terminal::vt_setmode(self, mode)
+    }
+}
+
+/// A window during which Ctrl-Alt-Fn and other switches away from the VT of
+/// a session are refused, so that they cannot interrupt a compositor that is
+/// still taking over the display, which corrupts the GPU state on some
+/// drivers. This is done by putting the VT into process mode, and answering
+/// every release request with a refusal.
+///
+/// The price is that the user cannot switch away from a session that hangs
+/// during this window, which is why it should be kept short. The kernel
+/// returns the VT to automatic mode if the worker dies, and a session which
+/// sets up its own process mode takes over from us immediately.
+pub struct SwitchGrace<T: VtModeOps> {
+    term: T,
+    deadline: Instant,
+    previous: Option<(SigAction, SigAction)>,
+}
+
+impl<T: VtModeOps> SwitchGrace<T> {
+    /// Start refusing switches away from the VT for the specified duration.
+    pub fn hold(term: T, duration: Duration) -> Result<SwitchGrace<T>, Error> {
+        let flags = SaFlags::SA_RESTART;
+        let release = SigAction::new(SigHandler::Handler(refuse_release), flags, SigSet::empty());
+        let acquire = SigAction::new(
+            SigHandler::Handler(acknowledge_acquire),
+            flags,
+            SigSet::empty(),
+        );
+
+        GRACE_FD.store(term.fd(), Ordering::SeqCst);
+        let previous = unsafe { (sigaction(RELSIG, &release)?, sigaction(ACQSIG, &acquire)?) };
+        let grace = SwitchGrace {
+            term,
+            deadline: Instant::now() + duration,
+            previous: Some(previous),
+        };
+
+        grace.term.vt_setmode(VtMode::Process {
+            relsig: RELSIG as u16,
+            acqsig: ACQSIG as u16,
+            frsig: MARKER,
+        })?;
+        Ok(grace)
+    }
+
+    /// Wait for the end of the window, and release the VT.
+    pub fn finish(self) {
+        let now = Instant::now();
+        if self.deadline > now {
+            thread::sleep(self.deadline - now);
+        }
+    }
+}
+
+impl<T: VtModeOps> Drop for SwitchGrace<T> {
+    fn drop(&mut self) {
+        // Leave the VT alone if the session has set up its own mode.
+        let ours = VtMode::Process {
+            relsig: RELSIG as u16,
+            acqsig: ACQSIG as u16,
+            frsig: MARKER,
+        };
+        match self.term.vt_getmode() {
+            Ok(mode) if mode == ours => {
+                if let Err(e) = self.term.vt_setmode(VtMode::Auto) {
+                    eprintln!("terminal: unable to release vt: {}", e);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => eprintln!("terminal: unable to release vt: {}", e),
+        }
+
+        GRACE_FD.store(-1, Ordering::SeqCst);
+        if let Some((release, acquire)) = self.previous.take() {
+            unsafe {
+                let _ = sigaction(RELSIG, &release);
+                let _ = sigaction(ACQSIG, &acquire);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{
+        sys::{
+            signal::raise,
+            wait::{waitpid, WaitStatus},
+        },
+        unistd::{fork, ForkResult},
+    };
+    use std::{cell::Cell, rc::Rc};
+
+    struct MockVt {
+        mode: Rc<Cell<VtMode>>,
+    }
+
+    impl VtModeOps for MockVt {
+        fn fd(&self) -> RawFd {
+            -1
+        }
+        fn vt_getmode(&self) -> Result<VtMode, Error> {
+            Ok(self.mode.get())
+        }
+        fn vt_setmode(&self, mode: VtMode) -> Result<(), Error> {
+            self.mode.set(mode);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn grace_window() {
+        // The signal dispositions are process-wide, so run in a child.
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let mode = Rc::new(Cell::new(VtMode::Auto));
+                let term = MockVt { mode: mode.clone() };
+                let start = Instant::now();
+                let grace = SwitchGrace::hold(term, Duration::from_millis(50)).unwrap();
+                let held = match mode.get() {
+                    VtMode::Process { relsig, acqsig, .. } => {
+                        relsig == RELSIG as u16 && acqsig == ACQSIG as u16
+                    }
+                    VtMode::Auto => false,
+                };
+
+                // A release request must not kill us.
+                raise(RELSIG).unwrap();
+                raise(ACQSIG).unwrap();
+
+                grace.finish();
+                let code = if !held {
+                    1
+                } else if start.elapsed() < Duration::from_millis(50) {
+                    2
+                } else if mode.get() != VtMode::Auto {
+                    3
+                } else {
+                    0
+                };
+                unsafe { libc::_exit(code) };
+            }
+        }
+    }
+
+    #[test]
+    fn session_mode_kept() {
+        // The signal dispositions are process-wide, so run in a child.
+        match fork().unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let mode = Rc::new(Cell::new(VtMode::Auto));
+                let term = MockVt { mode: mode.clone() };
+                let grace = SwitchGrace::hold(term, Duration::from_millis(0)).unwrap();
+
+                // A session taking over with the same signals.
+                let session = VtMode::Process {
+                    relsig: RELSIG as u16,
+                    acqsig: ACQSIG as u16,
+                    frsig: 0,
+                };
+                mode.set(session);
+                grace.finish();
+                unsafe { libc::_exit(if mode.get() == session { 0 } else { 1 }) };
+            }
+        }
+    }
+}
@@ -1,21 +1,29 @@
 use nix::{ioctl_read_bad, ioctl_write_int_bad, ioctl_write_ptr_bad};
 
 pub const KDSETMODE: u16 = 0x4B3A;
+pub const KDGETMODE: u16 = 0x4B3B;
 pub const KDTEXT: i32 = 0x00;
 pub const KDGRAPHICS: i32 = 0x01;
 pub const VT_OPENQRY: u16 = 0x5600;
+pub const VT_GETMODE: u16 = 0x5601;
 pub const VT_SETMODE: u16 = 0x5602;
 pub const VT_GETSTATE: u16 = 0x5603;
+pub const VT_RELDISP: u16 = 0x5605;
 pub const VT_ACTIVATE: u16 = 0x5606;
 pub const VT_WAITACTIVE: u16 = 0x5607;
 pub const VT_SETACTIVATE: u16 = 0x560F;
 pub const VT_AUTO: u8 = 0;
+pub const VT_PROCESS: u8 = 1;
+pub const VT_ACKACQ: i32 = 2;
 pub const TIOCSCTTY: u16 = 0x540E;
 
 ioctl_write_int_bad!(kd_setmode, KDSETMODE);
+ioctl_read_bad!(kd_getmode, KDGETMODE, i32);
 ioctl_write_int_bad!(vt_activate, VT_ACTIVATE);
 ioctl_write_int_bad!(vt_waitactive, VT_WAITACTIVE);
+ioctl_read_bad!(vt_getmode, VT_GETMODE, vt_mode);
 ioctl_write_ptr_bad!(vt_setmode, VT_SETMODE, vt_mode);
+ioctl_write_int_bad!(vt_reldisp, VT_RELDISP);
 ioctl_write_ptr_bad!(vt_setactivate, VT_SETACTIVATE, vt_setactivate);
 ioctl_read_bad!(vt_openqry, VT_OPENQRY, i64);
 ioctl_read_bad!(vt_getstate, VT_GETSTATE, vt_state);
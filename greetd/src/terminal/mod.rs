@@ -1,26 +1,54 @@
+pub mod grace;
 mod ioctl;
+pub mod setup;
+mod termios;
 
 use crate::error::Error;
 use nix::{
     fcntl::{open, OFlag},
     sys::stat::Mode,
-    unistd::{close, dup2, write},
+    unistd::{close, dup, dup2, write},
 };
 use std::{ffi::CStr, os::unix::io::RawFd};
 
+/// The highest VT number supported by the kernel, MAX_NR_CONSOLES.
+pub const MAX_VT: usize = 63;
+
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum KdMode {
     Text,
     Graphics,
 }
 
 impl KdMode {
-    fn to_const(&self) -> i32 {
+    fn to_const(self) -> i32 {
         match self {
             KdMode::Text => ioctl::KDTEXT,
             KdMode::Graphics => ioctl::KDGRAPHICS,
         }
     }
+
+    fn from_const(mode: i32) -> KdMode {
+        match mode {
+            ioctl::KDTEXT => KdMode::Text,
+            _ => KdMode::Graphics,
+        }
+    }
+}
+
+/// How VT switches away from and to a VT are handled. In process mode, the
+/// kernel asks the controlling process to release the VT with relsig, and
+/// announces its acquisition with acqsig. The kernel does not use frsig, but
+/// stores it, which makes it usable as a marker.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VtMode {
+    Auto,
+    Process {
+        relsig: u16,
+        acqsig: u16,
+        frsig: u16,
+    },
 }
 
 pub struct Terminal {
@@ -100,6 +128,18 @@ impl Terminal {
         }
     }
 
+    /// Retrieve the current kernel display mode.
+    pub fn kd_getmode(&self) -> Result<KdMode, Error> {
+        let mut mode: i32 = 0;
+        let ret = unsafe { ioctl::kd_getmode(self.fd, &mut mode as *mut i32) };
+
+        if let Err(v) = ret {
+            Err(format!("terminal: unable to get kernel display mode: {}", v).into())
+        } else {
+            Ok(KdMode::from_const(mode))
+        }
+    }
+
     /// Switches to the specified VT and waits for completion of switch.
     fn vt_activate(&self, target_vt: usize) -> Result<(), Error> {
         if let Err(v) = unsafe { ioctl::vt_activate(self.fd, target_vt as i32) } {
@@ -129,6 +169,54 @@ impl Terminal {
         }
     }
 
+    /// Retrieve the VT switching mode of this VT.
+    pub fn vt_getmode(&self) -> Result<VtMode, Error> {
+        let mut mode = ioctl::vt_mode {
+            mode: 0,
+            waitv: 0,
+            relsig: 0,
+            acqsig: 0,
+            frsig: 0,
+        };
+        let res = unsafe { ioctl::vt_getmode(self.fd, &mut mode as *mut ioctl::vt_mode) };
+
+        match res {
+            Err(v) => Err(format!("terminal: unable to get vt mode: {}", v).into()),
+            Ok(_) if mode.mode == ioctl::VT_PROCESS => Ok(VtMode::Process {
+                relsig: mode.relsig,
+                acqsig: mode.acqsig,
+                frsig: mode.frsig,
+            }),
+            Ok(_) => Ok(VtMode::Auto),
+        }
+    }
+
+    /// Set the VT switching mode of this VT. Process mode makes the calling
+    /// process the one that is signalled.
+    pub fn vt_setmode(&self, mode: VtMode) -> Result<(), Error> {
+        let mode = match mode {
+            VtMode::Auto => return self.vt_mode_clean(),
+            VtMode::Process {
+                relsig,
+                acqsig,
+                frsig,
+            } => ioctl::vt_mode {
+                mode: ioctl::VT_PROCESS,
+                waitv: 0,
+                relsig,
+                acqsig,
+                frsig,
+            },
+        };
+        let res = unsafe { ioctl::vt_setmode(self.fd, &mode) };
+
+        if let Err(v) = res {
+            Err(format!("terminal: unable to set vt mode: {}", v).into())
+        } else {
+            Ok(())
+        }
+    }
+
     /// Set a VT mode, switch to the VT and wait for its activation. On Linux,
     /// this will use VT_SETACTIVATE, which will both set the mode and switch
     /// to the VT under the kernel console lock. On other platforms,
@@ -207,6 +295,44 @@ impl Terminal {
         }
     }
 
+    /// Duplicate stdin, stdout and stderr of the current process, so that
+    /// they can be restored after term_connect_pipes.
+    pub fn std_fds_save() -> Result<[RawFd; 3], Error> {
+        let mut saved = [-1; 3];
+        for (fd, s) in saved.iter_mut().enumerate() {
+            match dup(fd as RawFd) {
+                Ok(new) => *s = new,
+                Err(e) => {
+                    Terminal::std_fds_close(saved);
+                    return Err(format!("terminal: unable to save pipes: {}", e).into());
+                }
+            }
+        }
+        Ok(saved)
+    }
+
+    /// Restore stdin, stdout and stderr of the current process from
+    /// duplicates made by std_fds_save, closing the duplicates.
+    pub fn std_fds_restore(saved: [RawFd; 3]) -> Result<(), Error> {
+        let res = dup2(saved[0], 0)
+            .and_then(|_| dup2(saved[1], 1))
+            .and_then(|_| dup2(saved[2], 2));
+        Terminal::std_fds_close(saved);
+
+        if let Err(v) = res {
+            Err(format!("terminal: unable to restore pipes: {}", v).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Close duplicates made by std_fds_save.
+    pub fn std_fds_close(saved: [RawFd; 3]) {
+        for fd in saved.iter().filter(|&&fd| fd >= 0) {
+            let _ = close(*fd);
+        }
+    }
+
     /// Clear this terminal by sending the appropciate escape codes to it. Only
     /// affects text mode.
     pub fn term_clear(&self) -> Result<(), Error> {
@@ -218,6 +344,13 @@ impl Terminal {
         }
     }
 
+    /// Reset the terminal settings, such as echo and canonical mode, to sane
+    /// defaults.
+    pub fn term_sane(&self) -> Result<(), Error> {
+        termios::reset(self.fd)
+            .map_err(|e| format!("terminal: unable to reset terminal settings: {}", e).into())
+    }
+
     // Forcibly take control of the terminal referred to by this fd.
     pub fn term_take_ctty(&self) -> Result<(), Error> {
         let res = unsafe { ioctl::term_tiocsctty(self.fd, 1) };
@@ -0,0 +1,246 @@
+use std::os::unix::io::RawFd;
+
+use super::{KdMode, Terminal};
+use crate::error::Error;
+
+/// The operations needed to set up the terminal of a session.
+pub trait TerminalOps {
+    fn kd_getmode(&self) -> Result<KdMode, Error>;
+    fn kd_setmode(&self, mode: KdMode) -> Result<(), Error>;
+    fn term_clear(&self) -> Result<(), Error>;
+    fn vt_get_current(&self) -> Result<usize, Error>;
+    fn vt_setactivate(&self, vt: usize) -> Result<(), Error>;
+    fn std_fds_save(&self) -> Result<[RawFd; 3], Error>;
+    fn std_fds_restore(&self, saved: [RawFd; 3]) -> Result<(), Error>;
+    fn std_fds_close(&self, saved: [RawFd; 3]);
+    fn term_connect_pipes(&self) -> Result<(), Error>;
+    fn term_take_ctty(&self) -> Result<(), Error>;
+}
+
+impl TerminalOps for Terminal {
+    fn kd_getmode(&self) -> Result<KdMode, Error> {
+        Terminal::kd_getmode(self)
+    }
+    fn kd_setmode(&self, mode: KdMode) -> Result<(), Error> {
+        Terminal::kd_setmode(self, mode)
+    }
+    fn term_clear(&self) -> Result<(), Error> {
+        Terminal::term_clear(self)
+    }
+    fn vt_get_current(&self) -> Result<usize, Error> {
+        Terminal::vt_get_current(self)
+    }
+    fn vt_setactivate(&self, vt: usize) -> Result<(), Error> {
+        Terminal::vt_setactivate(self, vt)
+    }
+    fn std_fds_save(&self) -> Result<[RawFd; 3], Error> {
+        Terminal::std_fds_save()
+    }
+    fn std_fds_restore(&self, saved: [RawFd; 3]) -> Result<(), Error> {
+        Terminal::std_fds_restore(saved)
+    }
+    fn std_fds_close(&self, saved: [RawFd; 3]) {
+        Terminal::std_fds_close(saved)
+    }
+    fn term_connect_pipes(&self) -> Result<(), Error> {
+        Terminal::term_connect_pipes(self)
+    }
+    fn term_take_ctty(&self) -> Result<(), Error> {
+        Terminal::term_take_ctty(self)
+    }
+}
+
+/// Prepare the terminal for a session on the specified VT, and make it our
+/// controlling terminal. A terminal that is taken over from a greeter that
+/// was running on it is not cleared, so that the session starts over what the
+/// greeter left, but it is still switched to, as the user may have switched
+/// away since. If any step fails,
+/// the kernel display mode and our stdin, stdout and stderr are restored
+/// before the error is returned, so that a failed login does not leave the
+/// VT half-configured.
+pub fn setup_session_terminal<T: TerminalOps>(
+    term: &T,
+    vt: usize,
+    switch: bool,
+    handoff: bool,
+) -> Result<(), Error> {
+    let prev_mode = term.kd_getmode()?;
+    let saved = term.std_fds_save()?;
+
+    let res = (|| {
+        // Set the target VT mode to text for compatibility. Other login managers
+        // set this to graphics, but that disallows start of textual applications,
+        // which greetd aims to support.
+        term.kd_setmode(KdMode::Text)?;
+
+        // Clear TTY so that it will be empty when we switch to it, unless
+        // taken over from a greeter.
+        if !handoff {
+            term.term_clear()?;
+        }
+
+        // A bit more work if a VT switch is required.
+        if switch && vt != term.vt_get_current()? {
+            // Perform a switch to the target VT, simultaneously resetting it
+            // to VT_AUTO.
+            term.vt_setactivate(vt)?;
+        }
+
+        // Connect std(in|out|err), and make this our controlling TTY.
+        term.term_connect_pipes()?;
+        term.term_take_ctty()
+    })();
+
+    match res {
+        Ok(()) => {
+            term.std_fds_close(saved);
+            Ok(())
+        }
+        Err(e) => {
+            if let Err(e) = term.std_fds_restore(saved) {
+                eprintln!("terminal: rollback failed: {}", e);
+            }
+            if let Err(e) = term.kd_setmode(prev_mode) {
+                eprintln!("terminal: rollback failed: {}", e);
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    const SAVED: [RawFd; 3] = [10, 11, 12];
+
+    struct MockTerminal {
+        fail_at: Option<&'static str>,
+        mode: Cell<KdMode>,
+        set_modes: RefCell<Vec<KdMode>>,
+        cleared: Cell<bool>,
+        vt: Cell<usize>,
+        connected: Cell<bool>,
+        saved: RefCell<Option<[RawFd; 3]>>,
+    }
+
+    impl MockTerminal {
+        fn new(fail_at: Option<&'static str>, mode: KdMode) -> MockTerminal {
+            MockTerminal {
+                fail_at,
+                mode: Cell::new(mode),
+                set_modes: RefCell::new(Vec::new()),
+                cleared: Cell::new(false),
+                vt: Cell::new(1),
+                connected: Cell::new(false),
+                saved: RefCell::new(None),
+            }
+        }
+
+        fn step(&self, name: &'static str) -> Result<(), Error> {
+            if self.fail_at == Some(name) {
+                Err(format!("terminal: {} failed", name).into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl TerminalOps for MockTerminal {
+        fn kd_getmode(&self) -> Result<KdMode, Error> {
+            Ok(self.mode.get())
+        }
+        fn kd_setmode(&self, mode: KdMode) -> Result<(), Error> {
+            // Model a failure half-way through, which still changes the mode.
+            self.mode.set(mode);
+            self.set_modes.borrow_mut().push(mode);
+            self.step("kd_setmode")
+        }
+        fn term_clear(&self) -> Result<(), Error> {
+            self.cleared.set(true);
+            self.step("term_clear")
+        }
+        fn vt_get_current(&self) -> Result<usize, Error> {
+            self.step("vt_get_current")?;
+            Ok(self.vt.get())
+        }
+        fn vt_setactivate(&self, vt: usize) -> Result<(), Error> {
+            self.step("vt_setactivate")?;
+            self.vt.set(vt);
+            Ok(())
+        }
+        fn std_fds_save(&self) -> Result<[RawFd; 3], Error> {
+            self.saved.replace(Some(SAVED));
+            Ok(SAVED)
+        }
+        fn std_fds_restore(&self, saved: [RawFd; 3]) -> Result<(), Error> {
+            assert_eq!(self.saved.replace(None), Some(saved));
+            self.connected.set(false);
+            Ok(())
+        }
+        fn std_fds_close(&self, saved: [RawFd; 3]) {
+            assert_eq!(self.saved.replace(None), Some(saved));
+        }
+        fn term_connect_pipes(&self) -> Result<(), Error> {
+            self.connected.set(true);
+            self.step("term_connect_pipes")
+        }
+        fn term_take_ctty(&self) -> Result<(), Error> {
+            self.step("term_take_ctty")
+        }
+    }
+
+    #[test]
+    fn setup() {
+        let term = MockTerminal::new(None, KdMode::Graphics);
+        setup_session_terminal(&term, 2, true, false).unwrap();
+        assert_eq!(term.mode.get(), KdMode::Text);
+        assert_eq!(term.vt.get(), 2);
+        assert!(term.cleared.get());
+        assert!(term.connected.get());
+        assert_eq!(*term.saved.borrow(), None);
+    }
+
+    #[test]
+    fn handoff() {
+        let term = MockTerminal::new(None, KdMode::Graphics);
+        setup_session_terminal(&term, 2, true, true).unwrap();
+        assert_eq!(term.mode.get(), KdMode::Text);
+        assert_eq!(term.vt.get(), 2);
+        assert!(!term.cleared.get());
+        assert!(term.connected.get());
+        assert_eq!(*term.saved.borrow(), None);
+    }
+
+    #[test]
+    fn rollback() {
+        for step in &[
+            "kd_setmode",
+            "term_clear",
+            "vt_get_current",
+            "vt_setactivate",
+            "term_connect_pipes",
+            "term_take_ctty",
+        ] {
+            // The mode is restored to what it was, whichever mode that is.
+            for &prev in &[KdMode::Graphics, KdMode::Text] {
+                let term = MockTerminal::new(Some(step), prev);
+                assert!(
+                    setup_session_terminal(&term, 2, true, false).is_err(),
+                    "{}",
+                    step
+                );
+                assert_eq!(
+                    *term.set_modes.borrow(),
+                    vec![KdMode::Text, prev],
+                    "{}",
+                    step
+                );
+                assert_eq!(term.mode.get(), prev, "{}", step);
+                assert!(!term.connected.get(), "{}", step);
+                assert_eq!(*term.saved.borrow(), None, "{}", step);
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::os::unix::io::RawFd;
+
+use nix::{
+    sys::termios::{
+        tcgetattr, tcsetattr, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg,
+        SpecialCharacterIndices, Termios,
+    },
+    Result,
+};
+
+/// Reset the terminal settings to those of stty sane, leaving the baud rate
+/// and character size alone, so that a session does not inherit modes such
+/// as disabled echo from whatever used the terminal before it.
+pub fn make_sane(termios: &mut Termios) {
+    termios.input_flags.remove(
+        InputFlags::IGNBRK
+            | InputFlags::INLCR
+            | InputFlags::IGNCR
+            | InputFlags::IXOFF
+            | InputFlags::IXANY,
+    );
+    termios.input_flags.insert(
+        InputFlags::BRKINT
+            | InputFlags::ICRNL
+            | InputFlags::IMAXBEL
+            | InputFlags::IXON
+            | InputFlags::IUTF8,
+    );
+
+    termios
+        .output_flags
+        .remove(OutputFlags::OCRNL | OutputFlags::ONOCR | OutputFlags::ONLRET | OutputFlags::OFILL);
+    termios
+        .output_flags
+        .insert(OutputFlags::OPOST | OutputFlags::ONLCR);
+
+    termios.control_flags.insert(ControlFlags::CREAD);
+
+    termios
+        .local_flags
+        .remove(LocalFlags::ECHONL | LocalFlags::NOFLSH | LocalFlags::TOSTOP);
+    termios.local_flags.insert(
+        LocalFlags::ISIG
+            | LocalFlags::ICANON
+            | LocalFlags::IEXTEN
+            | LocalFlags::ECHO
+            | LocalFlags::ECHOE
+            | LocalFlags::ECHOK
+            | LocalFlags::ECHOCTL
+            | LocalFlags::ECHOKE,
+    );
+
+    for &(idx, c) in &[
+        (SpecialCharacterIndices::VINTR, 0x03),
+        (SpecialCharacterIndices::VQUIT, 0x1c),
+        (SpecialCharacterIndices::VERASE, 0x7f),
+        (SpecialCharacterIndices::VKILL, 0x15),
+        (SpecialCharacterIndices::VEOF, 0x04),
+        (SpecialCharacterIndices::VSTART, 0x11),
+        (SpecialCharacterIndices::VSTOP, 0x13),
+        (SpecialCharacterIndices::VSUSP, 0x1a),
+        (SpecialCharacterIndices::VMIN, 1),
+        (SpecialCharacterIndices::VTIME, 0),
+    ] {
+        termios.control_chars[idx as usize] = c;
+    }
+}
+
+/// Apply sane settings to the terminal referred to by fd.
+pub fn reset(fd: RawFd) -> Result<()> {
+    let mut termios = tcgetattr(fd)?;
+    make_sane(&mut termios);
+    tcsetattr(fd, SetArg::TCSANOW, &termios)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{pty::openpty, sys::termios::cfmakeraw, unistd::close};
+
+    #[test]
+    fn sane_after_raw() {
+        let pty = openpty(None, None).unwrap();
+        let mut termios = tcgetattr(pty.slave).unwrap();
+        cfmakeraw(&mut termios);
+        termios.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &termios).unwrap();
+
+        reset(pty.slave).unwrap();
+        let termios = tcgetattr(pty.slave).unwrap();
+        assert!(termios
+            .local_flags
+            .contains(LocalFlags::ECHO | LocalFlags::ICANON | LocalFlags::ISIG));
+        assert!(termios.input_flags.contains(InputFlags::ICRNL));
+        assert!(termios
+            .output_flags
+            .contains(OutputFlags::OPOST | OutputFlags::ONLCR));
+        assert_eq!(
+            termios.control_chars[SpecialCharacterIndices::VINTR as usize],
+            0x03
+        );
+        assert_eq!(
+            termios.control_chars[SpecialCharacterIndices::VERASE as usize],
+            0x7f
+        );
+
+        close(pty.slave).unwrap();
+        close(pty.master).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! A panic in a session worker built like the shipped binary, with
+//! panic=abort, must still be reported to the parent.
+
+use std::{
+    os::unix::{io::AsRawFd, process::CommandExt, process::ExitStatusExt},
+    process::Command,
+};
+
+use greetd::session::worker::{
+    set_cloexec, socket_pair, LoginOptions, ParentToSessionChild, SessionChildToParent,
+    TerminalMode, MAX_MESSAGE_SIZE,
+};
+
+#[test]
+fn worker_binary_panic() {
+    let (parent, worker_sock) = socket_pair().unwrap();
+    let fd = worker_sock.as_raw_fd();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_greetd"));
+    cmd.arg("--session-worker")
+        .arg(fd.to_string())
+        .env("GREETD_TEST_WORKER_PANIC", "1");
+    unsafe {
+        cmd.pre_exec(move || {
+            set_cloexec(fd, false).map_err(|e| std::io::Error::other(e.to_string()))
+        });
+    }
+    let mut child = cmd.spawn().unwrap();
+    drop(worker_sock);
+
+    let mut data = [0; MAX_MESSAGE_SIZE];
+    let len = parent.recv(&mut data[..]).unwrap();
+    assert!(matches!(
+        serde_json::from_slice(&data[..len]).unwrap(),
+        SessionChildToParent::Ready { .. }
+    ));
+
+    let msg = ParentToSessionChild::InitiateLogin {
+        service: "greetd-test".to_string(),
+        class: "user".to_string(),
+        user: "nobody".to_string(),
+        authenticate: false,
+        tty: TerminalMode::Stdin,
+        source_profile: false,
+        options: Box::new(LoginOptions::default()),
+    };
+    parent.send(&serde_json::to_vec(&msg).unwrap()).unwrap();
+
+    let len = parent.recv(&mut data[..]).unwrap();
+    match serde_json::from_slice(&data[..len]).unwrap() {
+        SessionChildToParent::Error(e) => assert_eq!(
+            e.to_string(),
+            "session worker panicked: induced by GREETD_TEST_WORKER_PANIC"
+        ),
+        msg => panic!("expected Error, got: {:?}", msg),
+    }
+
+    // The worker exits rather than aborting.
+    let status = child.wait().unwrap();
+    assert_eq!(status.signal(), None);
+    assert_eq!(status.code(), Some(1));
+}
@@ -0,0 +1,137 @@
+//! The phases of a real login are timed as the session worker completes
+//! them, and reported before the session is started.
+
+use std::{
+    os::unix::{io::AsRawFd, net::UnixDatagram, process::CommandExt},
+    process::Command,
+};
+
+use greetd::session::worker::{
+    set_cloexec, socket_pair, LoginOptions, ParentToSessionChild, SessionChildToParent,
+    TerminalMode, MAX_MESSAGE_SIZE,
+};
+
+/// The fixture service, found in tests/pam.d of this crate, which admits
+/// anyone.
+const FIXTURE_SERVICE: &str = "greetd-selftest";
+
+fn send(sock: &UnixDatagram, msg: &ParentToSessionChild) {
+    sock.send(&serde_json::to_vec(msg).unwrap()).unwrap();
+}
+
+fn recv(sock: &UnixDatagram) -> SessionChildToParent {
+    let mut data = [0; MAX_MESSAGE_SIZE];
+    let len = sock.recv(&mut data[..]).unwrap();
+    serde_json::from_slice(&data[..len]).unwrap()
+}
+
+/// Log root in with a trivial session on the terminal, and return the
+/// phases the worker reported, in order.
+fn login_phases(tty: TerminalMode) -> Vec<String> {
+    let (parent, worker_sock) = socket_pair().unwrap();
+    let fd = worker_sock.as_raw_fd();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_greetd"));
+    cmd.arg("--session-worker").arg(fd.to_string());
+    unsafe {
+        cmd.pre_exec(move || {
+            set_cloexec(fd, false).map_err(|e| std::io::Error::other(e.to_string()))
+        });
+    }
+    let mut child = cmd.spawn().unwrap();
+    drop(worker_sock);
+
+    assert!(matches!(recv(&parent), SessionChildToParent::Ready { .. }));
+    send(
+        &parent,
+        &ParentToSessionChild::InitiateLogin {
+            service: FIXTURE_SERVICE.to_string(),
+            class: "user".to_string(),
+            user: "root".to_string(),
+            authenticate: true,
+            tty,
+            source_profile: false,
+            options: Box::new(LoginOptions {
+                report_timings: true,
+                ..Default::default()
+            }),
+        },
+    );
+    loop {
+        match recv(&parent) {
+            SessionChildToParent::PamMessage { style, .. } => {
+                let resp = if style.is_prompt() {
+                    Some("timings".to_string())
+                } else {
+                    None
+                };
+                send(&parent, &ParentToSessionChild::PamResponse { resp });
+            }
+            SessionChildToParent::Success => break,
+            msg => panic!("expected PamMessage or Success, got: {:?}", msg),
+        }
+    }
+
+    send(
+        &parent,
+        &ParentToSessionChild::Args {
+            cmd: vec!["/bin/true".to_string()],
+        },
+    );
+    assert!(matches!(recv(&parent), SessionChildToParent::Success));
+
+    send(&parent, &ParentToSessionChild::Start);
+    let mut phases = None;
+    loop {
+        match recv(&parent) {
+            SessionChildToParent::Timings(timings) => {
+                phases = Some(timings.0.into_iter().map(|t| t.phase).collect())
+            }
+            SessionChildToParent::SessionStarted { .. } => (),
+            SessionChildToParent::FinalChildPid(_) => break,
+            msg => panic!("expected Timings or the session, got: {:?}", msg),
+        }
+    }
+    assert!(child.wait().unwrap().success());
+    phases.expect("no timings reported")
+}
+
+/// Run with `sudo cargo test -- --ignored stdin_login_timings`, after
+/// installing tests/pam.d/greetd-selftest in /etc/pam.d.
+#[test]
+#[ignore]
+fn stdin_login_timings() {
+    assert_eq!(
+        login_phases(TerminalMode::Stdin),
+        vec![
+            "authenticate",
+            "acct_mgmt",
+            "setcred",
+            "open_session",
+            "fork"
+        ]
+    );
+}
+
+/// Run with `sudo cargo test -- --ignored terminal_login_timings`, after
+/// installing tests/pam.d/greetd-selftest in /etc/pam.d. The session is run
+/// on VT 9, which is cleared, without switching to it.
+#[test]
+#[ignore]
+fn terminal_login_timings() {
+    let tty = TerminalMode::Terminal {
+        path: "/dev/tty9".to_string(),
+        vt: 9,
+        switch: false,
+    };
+    assert_eq!(
+        login_phases(tty),
+        vec![
+            "authenticate",
+            "acct_mgmt",
+            "setcred",
+            "terminal",
+            "open_session",
+            "fork"
+        ]
+    );
+}
@@ -10,7 +10,13 @@
 //!
 //! fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     let mut stream = UnixStream::connect(env::var("GREETD_SOCK")?)?;
-//!     Request::CreateSession { username: "john".to_string() }.write_to(&mut stream)?;
+//!     Request::CreateSession {
+//!         username: "john".to_string(),
+//!         session_cookie: None,
+//!         xkb_layout: None,
+//!         xkb_variant: None,
+//!         xkb_options: None,
+//!     }.write_to(&mut stream)?;
 //!     let resp = Response::read_from(&mut stream)?;
 //!     Ok(())
 //! }
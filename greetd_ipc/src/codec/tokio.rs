@@ -11,7 +11,13 @@
 //! #[tokio::main]
 //! async fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     let mut stream = UnixStream::connect(env::var("GREETD_SOCK")?).await?;
-//!     Request::CreateSession { username: "john".to_string() }.write_to(&mut stream).await?;
+//!     Request::CreateSession {
+//!         username: "john".to_string(),
+//!         session_cookie: None,
+//!         xkb_layout: None,
+//!         xkb_variant: None,
+//!         xkb_options: None,
+//!     }.write_to(&mut stream).await?;
 //!     let resp = Response::read_from(&mut stream).await?;
 //!     Ok(())
 //! }
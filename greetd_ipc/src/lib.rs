@@ -63,7 +63,27 @@ pub enum Request {
     /// If a login flow needs to be aborted at any point, send
     /// Request::CancelSession. Note that the session is cancelled
     /// automatically on error.
-    CreateSession { username: String },
+    ///
+    /// A session cookie, an opaque token of the greeter's choosing, is handed
+    /// to the session, and only to the session, in the GREETD_SESSION_COOKIE
+    /// environment variable. greetd neither logs it nor exposes it to PAM.
+    /// It may be left out.
+    ///
+    /// The keyboard layout, variant and options selected in the greeter, if
+    /// any, are exported to the session as XKB_DEFAULT_LAYOUT,
+    /// XKB_DEFAULT_VARIANT and XKB_DEFAULT_OPTIONS, as picked up by Wayland
+    /// compositors. Each may be left out.
+    CreateSession {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_cookie: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        xkb_layout: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        xkb_variant: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        xkb_options: Option<String>,
+    },
 
     /// PostAuthMessageResponse responds to the last auth message, and returns
     /// either a Response::AuthMessage, Response::Success or Response::Failure.
@@ -111,6 +131,21 @@ pub enum AuthMessageType {
     Error,
 }
 
+/// The severity of a Response::AuthMessage, for greeters that filter or
+/// style messages by it. Serialized as snake_case.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// An error, such as a failed authentication step.
+    Error,
+
+    /// A message that needs the attention of the user, such as a question.
+    Notice,
+
+    /// An informational message.
+    Info,
+}
+
 /// A response from greetd to a greeter. The request type is internally tagged
 /// with the"type" field, with the type written in snake_case.
 ///
@@ -146,8 +181,13 @@ pub enum Response {
     /// died in the original "Land Before Time". It is therefore important that
     /// no assumptions are made about the questions that will be asked, and
     /// attempts to automatically answer these questions should not be made.
+    ///
+    /// The severity is not sent by versions of greetd that predate it, in
+    /// which case it is None.
     AuthMessage {
         auth_message_type: AuthMessageType,
         auth_message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth_message_severity: Option<Severity>,
     },
 }